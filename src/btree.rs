@@ -1,137 +1,837 @@
-use std::{fmt::Debug, io, marker::PhantomData};
+use std::{
+    alloc::{self, Layout},
+    borrow::Cow,
+    cmp::Ordering,
+    fmt::{self, Debug, Display},
+    io,
+    marker::PhantomData,
+    ops::{Bound, RangeBounds, RangeFull},
+    ptr::NonNull,
+};
 
-use crate::{slotted_branch::SlottedBranch, slotted_leaf::SlottedLeaf, visualize::Graphviz};
+use crate::{
+    cursor::Cursor,
+    entry::{Entry, OccupiedEntry, VacantEntry},
+    flex::{ptr_to_bytes, Flex, DEFAULT_SPLIT_WINDOW, MAX_FENCES_LEN},
+    iter::{Iter, Keys, Prefix, Range, Values},
+    page_alloc::{Heap, PageAllocator},
+    slotted_branch::SlottedBranch,
+    slotted_leaf::{FromValue, SlottedLeaf},
+    visualize::Graphviz,
+    PAGE_SIZE,
+};
 
 // this is mainly cosmetic, since we just interpret based on tree height
 // Values stored in the tree are nodes too.. I cannot be asked to properly type this if I have to cast
 // between them all the time anyways
 pub type Node = *mut ();
 
+// `V` is whatever the old value is read back as. The tree itself only deals in pointers, the
+// inline value API gets the bytes. A split carries the value the insert replaced, if any, up to
+// where it is absorbed
 #[derive(Debug, PartialEq)]
-pub enum InsertResultIntern {
+pub enum InsertResultIntern<V = Node> {
     Inserted,
-    Split(String, Node),
-    Replaced(Node),
+    Split(Vec<u8>, Node, Option<V>),
+    Replaced(V),
+    Present(V),
 }
 
-impl Into<InsertResult> for InsertResultIntern {
-    fn into(self) -> InsertResult {
+impl<V> InsertResultIntern<V> {
+    // what an insert that split a page below comes to once the split has been taken in
+    pub(crate) fn absorbed(replaced: Option<V>) -> Self {
+        match replaced {
+            Some(old) => InsertResultIntern::Replaced(old),
+            None => InsertResultIntern::Inserted,
+        }
+    }
+
+    // the result of taking in a split from below, which may have replaced a value
+    pub(crate) fn with_replaced(self, replaced: Option<V>) -> Self {
         match self {
-            InsertResultIntern::Replaced(e) => InsertResult::Replaced(e),
-            InsertResultIntern::Inserted => InsertResult::Inserted,
-            InsertResultIntern::Split(_, _) => {
-                panic!("Cannot convert split result into InsertResult")
+            InsertResultIntern::Inserted => Self::absorbed(replaced),
+            InsertResultIntern::Split(separator, node, _) => {
+                InsertResultIntern::Split(separator, node, replaced)
             }
+            res => res,
         }
     }
 }
 
-#[derive(Debug)]
+impl TryFrom<InsertResultIntern> for InsertResult {
+    type Error = BTreeError;
+
+    fn try_from(res: InsertResultIntern) -> Result<Self, BTreeError> {
+        match res {
+            InsertResultIntern::Replaced(e) => Ok(InsertResult::Replaced(e)),
+            InsertResultIntern::Present(e) => Ok(InsertResult::Present(e)),
+            InsertResultIntern::Inserted => Ok(InsertResult::Inserted),
+            InsertResultIntern::Split(..) => Err(BTreeError::UnhandledSplit),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum InsertResult {
     Inserted,
+    // the key already existed and its old value was overwritten
     Replaced(Node),
+    // the key already existed and its value was kept. Only returned by `insert_if_absent`
+    Present(Node),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BTreeError {
+    // a branch is missing its rightmost child, or a child pointer is null
+    InvalidLayout,
+    // a full page had no two neighbouring keys to put a separator between
+    NoSeparator,
+    // inline values can be at most `MAX_VALUE_LEN` bytes long
+    ValueTooLarge { len: usize },
+    // a page split was not passed on to the parent
+    UnhandledSplit,
+    // the allocator has no pages left for the splits an insert may need
+    OutOfPages,
+    // a pointer was asked for where a value of this many bytes is stored inline
+    ValueSize { len: usize },
+    // the allocator could not read a page back from where it keeps it
+    PageRead(io::ErrorKind),
+}
+
+impl Display for BTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BTreeError::InvalidLayout => write!(f, "invalid branch layout"),
+            BTreeError::NoSeparator => write!(f, "could not find a suitable separator"),
+            BTreeError::ValueTooLarge { len } => {
+                write!(f, "value of {len} bytes is too large to store inline")
+            }
+            BTreeError::UnhandledSplit => write!(f, "page split was not handled by the parent"),
+            BTreeError::OutOfPages => write!(f, "no pages left to allocate"),
+            BTreeError::ValueSize { len } => write!(f, "value of {len} bytes is not a pointer"),
+            BTreeError::PageRead(kind) => write!(f, "could not read a page: {kind}"),
+        }
+    }
 }
 
+impl std::error::Error for BTreeError {}
+
+#[derive(Debug, PartialEq)]
+pub enum BulkLoadError {
+    // fill factors have to be in (0, 1]
+    FillFactor,
+    // the key at this position of the input is smaller than the one before
+    Unsorted { index: usize },
+    // the key at this position of the input is equal to the one before
+    Duplicate { index: usize },
+    // the allocator ran out of pages before the tree was built
+    OutOfPages,
+}
+
+impl Display for BulkLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BulkLoadError::FillFactor => write!(f, "fill factor must be in (0, 1]"),
+            BulkLoadError::Unsorted { index } => write!(f, "input is not sorted at entry {index}"),
+            BulkLoadError::Duplicate { index } => write!(f, "duplicate key at entry {index}"),
+            BulkLoadError::OutOfPages => write!(f, "no pages left to allocate"),
+        }
+    }
+}
+
+impl std::error::Error for BulkLoadError {}
+
 #[derive(Debug)]
-pub struct BTree<T: Debug> {
+pub struct BTree<T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
     height: usize,
     root: Node,
+    split_window: usize,
+    alloc: A,
     boo: PhantomData<T>,
 }
 
+/// How many children the branches of a tree have. Created by [`BTree::fan_out`].
+#[derive(Debug, PartialEq)]
+pub struct FanOut {
+    pub branches: usize,
+    pub children: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl FanOut {
+    pub fn average(&self) -> f64 {
+        self.children as f64 / self.branches.max(1) as f64
+    }
+}
+
+impl Display for FanOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} branches with {:.1} children on average ({} to {})",
+            self.branches,
+            self.average(),
+            self.min,
+            self.max
+        )
+    }
+}
+
 impl<T: Debug> BTree<T> {
     pub fn new() -> Self {
-        // create a boxed node for the root, then immediately unbox into raw
-        let root = Box::into_raw(Box::new(SlottedLeaf::<T>::new()));
-        Self {
+        Self::with_page_size()
+    }
+}
+
+impl<T: Debug, const P: usize> BTree<T, P> {
+    /// An empty tree on pages of `P` bytes, like `BTree::<T, 16384>::with_page_size()`. Larger
+    /// pages make for a flatter tree, but every split and merge moves more bytes
+    pub fn with_page_size() -> Self {
+        Self::new_in(Heap)
+    }
+
+    /// Builds a tree from entries sorted by key without going through `insert`. Leaves are filled
+    /// up to `fill_factor` of a page from left to right, then the branch levels are put on top of
+    /// them. A fill factor below 1 leaves room for later inserts before pages start to split.
+    pub fn from_sorted_iter<K: AsRef<[u8]>, I: IntoIterator<Item = (K, *mut T)>>(
+        entries: I,
+        fill_factor: f64,
+    ) -> Result<Self, BulkLoadError> {
+        Self::from_sorted_iter_in(entries, fill_factor, Heap)
+    }
+}
+
+impl<T: Debug, const P: usize, A: PageAllocator> BTree<T, P, A> {
+    /// An empty tree that takes its pages from `alloc`, and gives them back to it once they are
+    /// no longer needed. Aborts if there is no page for the root, like a failed allocation does
+    /// for a `Box`
+    pub fn new_in(alloc: A) -> Self {
+        Self::try_new_in(alloc)
+            .unwrap_or_else(|_| alloc::handle_alloc_error(Layout::new::<SlottedLeaf<T, P>>()))
+    }
+
+    /// Like `new_in`, but fails if `alloc` has no page left for the root
+    pub fn try_new_in(alloc: A) -> Result<Self, BTreeError> {
+        let root = alloc.alloc_node(SlottedLeaf::<T, P>::new())?;
+
+        Ok(Self {
             height: 0,
             root: root as Node,
+            split_window: DEFAULT_SPLIT_WINDOW,
+            alloc,
+            boo: PhantomData,
+        })
+    }
+
+    /// A tree around nodes that were put together elsewhere, like ones read back from a data
+    /// file. Their pages, overflow pages included, have to come from `alloc`
+    pub(crate) fn from_root(root: Node, height: usize, alloc: A) -> Self {
+        Self {
+            height,
+            root,
+            split_window: DEFAULT_SPLIT_WINDOW,
+            alloc,
             boo: PhantomData,
         }
     }
 
-    fn grow_leaf(&mut self, separator: &str, right: Node) {
-        let new_root = Box::into_raw(Box::new(SlottedBranch::<T>::new(
-            self.root, right, separator,
-        )));
-        self.root = new_root as Node;
-        self.height += 1;
+    /// Like `from_sorted_iter`, with the pages taken from `alloc`
+    pub fn from_sorted_iter_in<K: AsRef<[u8]>, I: IntoIterator<Item = (K, *mut T)>>(
+        entries: I,
+        fill_factor: f64,
+        alloc: A,
+    ) -> Result<Self, BulkLoadError> {
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(BulkLoadError::FillFactor);
+        }
+
+        let capacity = (Flex::<P>::DATA_LEN as f64 * fill_factor) as usize;
+
+        // put into their pages right away, so we can link them up once they are done
+        let mut leaves = vec![];
+        let mut separators = vec![];
+
+        if let Err(err) = Self::load_leaves(entries, capacity, &alloc, &mut leaves, &mut separators)
+        {
+            for leaf in leaves {
+                Self::drop_node(&alloc, leaf as Node, 0);
+            }
+
+            return Err(err);
+        }
+
+        for (index, &leaf) in leaves.iter().enumerate() {
+            let lower = index
+                .checked_sub(1)
+                .map_or(&[][..], |prev| &separators[prev]);
+            unsafe { &mut *Self::leaf(&alloc, leaf as Node) }
+                .set_fences(lower, separators.get(index).map(Vec::as_slice));
+        }
+
+        for pair in leaves.windows(2) {
+            SlottedLeaf::<T, P>::link(pair[0] as Node, pair[1] as Node, &alloc);
+        }
+
+        let mut children: Vec<_> = leaves.into_iter().map(|leaf| leaf as Node).collect();
+        let mut height = 0;
+
+        while children.len() > 1 {
+            match Self::build_level(&children, &separators, capacity, &alloc) {
+                Ok(level) => (children, separators) = level,
+                Err(_) => {
+                    for &child in &children {
+                        Self::drop_node(&alloc, child, height);
+                    }
+
+                    return Err(BulkLoadError::OutOfPages);
+                }
+            }
+
+            height += 1;
+        }
+
+        Ok(Self {
+            height,
+            root: children[0],
+            split_window: DEFAULT_SPLIT_WINDOW,
+            alloc,
+            boo: PhantomData,
+        })
+    }
+
+    // fills leaves from the sorted `entries`. Every full leaf adds a separator between it and the
+    // next one. Keys too long for their page go into overflow pages from `alloc` as well
+    fn load_leaves<K: AsRef<[u8]>, I: IntoIterator<Item = (K, *mut T)>>(
+        entries: I,
+        capacity: usize,
+        alloc: &A,
+        leaves: &mut Vec<*mut SlottedLeaf<T, P>>,
+        separators: &mut Vec<Vec<u8>>,
+    ) -> Result<(), BulkLoadError> {
+        let new_leaf = || {
+            alloc
+                .alloc_node(SlottedLeaf::<T, P>::new())
+                .map_err(|_| BulkLoadError::OutOfPages)
+        };
+
+        leaves.push(new_leaf()?);
+        let mut prev: Option<K> = None;
+
+        for (index, (key, value)) in entries.into_iter().enumerate() {
+            if let Some(prev) = &prev {
+                match prev.as_ref().cmp(key.as_ref()) {
+                    Ordering::Less => {}
+                    Ordering::Equal => return Err(BulkLoadError::Duplicate { index }),
+                    Ordering::Greater => return Err(BulkLoadError::Unsorted { index }),
+                }
+            }
+
+            let leaf = unsafe { &mut *Self::leaf(alloc, *leaves.last().unwrap() as Node) };
+            let value = ptr_to_bytes(value as Node);
+            let entry_bytes = SlottedLeaf::<T, P>::entry_size(key.as_ref(), &value);
+
+            // the fences only get added once the leaf is done, so leave room for them
+            if leaf.size() > 0
+                && (leaf.live_bytes() + entry_bytes > capacity
+                    || leaf.unused_bytes() < entry_bytes + MAX_FENCES_LEN)
+            {
+                let last_key = leaf.key_at(leaf.size() - 1);
+                let (separator, _) =
+                    SlottedLeaf::<T, P>::get_smallest_separator(&last_key, key.as_ref(), u16::MAX)
+                        .expect("Input is sorted");
+
+                separators.push(separator.to_owned());
+                leaves.push(new_leaf()?);
+            }
+
+            unsafe { &mut *Self::leaf(alloc, *leaves.last().unwrap() as Node) }
+                .push(key.as_ref(), &value, alloc)
+                .map_err(|_| BulkLoadError::OutOfPages)?;
+            prev = Some(key);
+        }
+
+        Ok(())
     }
 
-    fn grow_branch(&mut self, separator: &str, left: Node) {
-        let new_root = Box::into_raw(Box::new(SlottedBranch::<T>::new(
-            left, self.root, separator,
-        )));
+    // packs `children` into as few branches as the capacity allows. `separators[i]` sits between
+    // `children[i]` and `children[i + 1]`. Returns the new branches and the separators between them.
+    // Separators too long for a page go into overflow pages from `alloc`
+    fn build_level(
+        children: &[Node],
+        separators: &[Vec<u8>],
+        capacity: usize,
+        alloc: &A,
+    ) -> Result<(Vec<Node>, Vec<Vec<u8>>), BTreeError> {
+        let mut groups = vec![];
+        let mut start = 0;
+        let mut bytes = 0;
+        // the fences only get added by `from_entries`, so leave room for them
+        let capacity = capacity.min(Flex::<P>::DATA_LEN - MAX_FENCES_LEN);
+
+        for (i, separator) in separators.iter().enumerate() {
+            let entry_bytes = SlottedBranch::<T, P>::entry_size(separator);
+
+            // a branch always takes two children, however little the capacity leaves
+            if bytes + entry_bytes > capacity && i > start {
+                groups.push(start..i + 1);
+                start = i + 1;
+                bytes = 0;
+            } else {
+                bytes += entry_bytes;
+            }
+        }
+
+        groups.push(start..children.len());
+
+        // a branch with a single child has nothing to search. Give it one from its neighbour,
+        // or join the neighbour when that one has none to spare
+        if groups.len() > 1 && groups[groups.len() - 1].len() == 1 {
+            let last = groups.pop().unwrap();
+            let prev = groups.last_mut().unwrap();
+
+            if prev.len() > 2 {
+                prev.end -= 1;
+                let start = prev.end;
+                groups.push(start..last.end);
+            } else {
+                prev.end = last.end;
+            }
+        }
+
+        let mut branches = Vec::with_capacity(groups.len());
+        let mut upper_separators = Vec::with_capacity(groups.len() - 1);
+
+        // the children are still the caller's, only the branches over them go
+        let release = |branches: Vec<Node>| {
+            for branch in branches {
+                unsafe { &*Self::branch(alloc, branch) }.release_keys(alloc);
+                unsafe { alloc.free_node(branch as *mut SlottedBranch<T, P>) };
+            }
+        };
+
+        for group in groups {
+            let last = group.end - 1;
+            let lower = group
+                .start
+                .checked_sub(1)
+                .map_or(&[][..], |prev| &separators[prev]);
+            let upper = separators.get(last).map(Vec::as_slice);
+
+            let mut keys = Vec::with_capacity(group.len() - 1);
+
+            for i in group.clone().take(group.len() - 1) {
+                match Flex::<P>::store_key(&[], &separators[i], alloc) {
+                    Ok(key) => keys.push(key),
+                    Err(err) => {
+                        keys.iter()
+                            .for_each(|key| Flex::<P>::release_key(key, alloc));
+                        release(branches);
+                        return Err(err);
+                    }
+                }
+            }
+
+            let entries = keys.iter().zip(&children[group.clone()]);
+            let branch = SlottedBranch::<T, P>::from_entries(
+                entries.map(|(key, &child)| (&**key, child)),
+                NonNull::new(children[last]),
+                lower,
+                upper,
+            );
+
+            match alloc.alloc_node(branch) {
+                Ok(branch) => branches.push(branch as Node),
+                Err(err) => {
+                    keys.iter()
+                        .for_each(|key| Flex::<P>::release_key(key, alloc));
+                    release(branches);
+                    return Err(err);
+                }
+            }
+
+            if last < separators.len() {
+                upper_separators.push(separators[last].clone());
+            }
+        }
+
+        Ok((branches, upper_separators))
+    }
+
+    // the leaf `node` refers to, wherever `alloc` keeps it
+    fn leaf(alloc: &A, node: Node) -> *mut SlottedLeaf<T, P> {
+        alloc.page(node) as *mut SlottedLeaf<T, P>
+    }
+
+    fn branch(alloc: &A, node: Node) -> *mut SlottedBranch<T, P> {
+        alloc.page(node) as *mut SlottedBranch<T, P>
+    }
+
+    pub(crate) fn grow_leaf(&mut self, separator: &[u8], right: Node) -> Result<(), BTreeError> {
+        self.grow(self.root, right, separator)
+    }
+
+    pub(crate) fn grow_branch(&mut self, separator: &[u8], left: Node) -> Result<(), BTreeError> {
+        self.grow(left, self.root, separator)
+    }
+
+    // puts a new root with a single separator on top of the tree
+    fn grow(&mut self, left: Node, right: Node, separator: &[u8]) -> Result<(), BTreeError> {
+        let separator = Flex::<P>::store_key(&[], separator, &self.alloc)?;
+
+        let new_root = self
+            .alloc
+            .alloc_node(SlottedBranch::<T, P>::new(left, right, &separator))
+            .inspect_err(|_| Flex::<P>::release_key(&separator, &self.alloc))?;
+
         self.root = new_root as Node;
         self.height += 1;
+        Ok(())
+    }
+
+    // an insert splits at most one page on every level and puts a new root on top. Checked before
+    // anything is changed, so running out of pages can't leave a split half done. A long key
+    // takes overflow pages as well, once in its leaf and once more for the separator a split
+    // makes of it or of the longest key already in the leaf
+    pub(crate) fn check_pages(&self, key: &[u8]) -> Result<(), BTreeError> {
+        let Some(pages) = self.alloc.available_pages() else {
+            return Ok(());
+        };
+
+        let leaf = unsafe { &*self.try_leaf_for(key)? };
+        let separator_len = key.len().max(leaf.longest_key());
+
+        let needed = self.height
+            + 2
+            + Flex::<P>::overflow_pages(key.len())
+            + Flex::<P>::overflow_pages(separator_len);
+
+        match pages < needed {
+            true => Err(BTreeError::OutOfPages),
+            false => Ok(()),
+        }
+    }
+
+    /// Inserts `value` under `key`, overwriting and returning the previous value if there was one.
+    pub fn insert<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: *mut T,
+    ) -> Result<InsertResult, BTreeError> {
+        self.insert_with(key.as_ref(), value, true)
+    }
+
+    /// Inserts `value` only if `key` is not in the tree yet. Otherwise the existing value is kept
+    /// and returned as `InsertResult::Present`.
+    pub fn insert_if_absent<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: *mut T,
+    ) -> Result<InsertResult, BTreeError> {
+        self.insert_with(key.as_ref(), value, false)
     }
 
-    pub fn insert(&mut self, key: &str, value: *mut T) -> InsertResult {
+    fn insert_with(
+        &mut self,
+        key: &[u8],
+        value: *mut T,
+        replace: bool,
+    ) -> Result<InsertResult, BTreeError> {
         // we handle data behind opaque pointers. It's not interesting for us what is actually
         // inside
-        let value = value as Node;
+        self.insert_entry(key, &ptr_to_bytes(value as Node), replace)?
+            .try_into()
+    }
+
+    /// Stores `value` inline in the leaf instead of a pointer to it and returns the value it
+    /// replaced. Values can be at most `MAX_VALUE_LEN` bytes long.
+    pub fn insert_value<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, BTreeError> {
+        match self.insert_entry::<Vec<u8>>(key.as_ref(), value, true)? {
+            InsertResultIntern::Replaced(old) => Ok(Some(old)),
+            _ => Ok(None),
+        }
+    }
+
+    fn insert_entry<V: FromValue>(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        replace: bool,
+    ) -> Result<InsertResultIntern<V>, BTreeError> {
+        self.alloc.evict();
+        self.check_pages(key)?;
 
         if self.height == 0 {
             // root is a leaf
-            let leaf_ptr = unsafe { &mut *(self.root as *mut SlottedLeaf<T>) };
-            let res = leaf_ptr.insert(key, value);
+            let leaf_ptr =
+                unsafe { &mut *(self.alloc.try_page(self.root)? as *mut SlottedLeaf<T, P>) };
+            let res = leaf_ptr.insert_with(key, value, replace, self.split_window, &self.alloc)?;
 
-            let InsertResultIntern::Split(separator, node) = res else {
-                return res.into();
+            let InsertResultIntern::Split(separator, node, replaced) = res else {
+                return Ok(res);
             };
 
-            self.grow_leaf(&separator, node);
-            return InsertResult::Inserted;
+            self.grow_leaf(&separator, node)?;
+            return Ok(InsertResultIntern::absorbed(replaced));
         }
 
-        let branch_ptr = unsafe { &mut *(self.root as *mut SlottedBranch<T>) };
-        let res = branch_ptr.insert(key, value, self.height);
+        let branch_ptr =
+            unsafe { &mut *(self.alloc.try_page(self.root)? as *mut SlottedBranch<T, P>) };
+        let res = branch_ptr.insert(
+            key,
+            value,
+            self.height,
+            replace,
+            self.split_window,
+            &self.alloc,
+        )?;
 
-        let InsertResultIntern::Split(separator, node) = res else {
-            return res.into();
+        let InsertResultIntern::Split(separator, node, replaced) = res else {
+            return Ok(res);
         };
 
-        self.grow_branch(&separator, node);
-        InsertResult::Inserted
+        self.grow_branch(&separator, node)?;
+        Ok(InsertResultIntern::absorbed(replaced))
+    }
+
+    /// Looks up the place for `key` in a single descent, so it can be read, updated or filled in
+    /// without searching the tree again.
+    pub fn entry<K: AsRef<[u8]> + ?Sized>(&mut self, key: &K) -> Entry<'_, T, P, A> {
+        let key = key.as_ref();
+        let mut path = Vec::with_capacity(self.height);
+        let mut node = self.root;
+
+        self.alloc.evict();
+
+        for _ in 0..self.height {
+            let branch = unsafe { &mut *Self::branch(&self.alloc, node) };
+            let index = branch.get_upper_bound(key);
+
+            path.push((std::ptr::from_mut(branch), index));
+            node = branch.child_at(index);
+        }
+
+        let leaf_ptr = Self::leaf(&self.alloc, node);
+        let leaf = unsafe { &*leaf_ptr };
+        let index = leaf.get_upper_bound(key);
+
+        if index < leaf.size() && leaf.is_key_at(index, key) {
+            Entry::Occupied(OccupiedEntry::new(
+                leaf_ptr,
+                index,
+                key.to_owned(),
+                &self.alloc,
+            ))
+        } else {
+            Entry::Vacant(VacantEntry::new(self, key.to_owned(), path, leaf_ptr))
+        }
     }
 
-    pub fn get(&self, key: &str) -> Option<*mut T> {
+    pub fn get<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Result<Option<*mut T>, BTreeError> {
+        let key = key.as_ref();
+        let root = self.alloc.try_page(self.root)?;
+
         if self.height == 0 {
-            let leaf = unsafe { &*(self.root as *mut SlottedLeaf<T>) };
-            leaf.get(key)
+            let leaf = unsafe { &*(root as *mut SlottedLeaf<T, P>) };
+            Ok(leaf.get(key))
         } else {
-            let branch = unsafe { &*(self.root as *mut SlottedBranch<T>) };
-            branch.get(key, self.height)
+            let branch = unsafe { &*(root as *mut SlottedBranch<T, P>) };
+            branch.get(key, self.height, &self.alloc)
         }
     }
 
-    fn drop_branch(branch: &mut SlottedBranch<T>, height: usize) {
-        let (nodes, _) = branch.data.interpret(&branch.header);
+    /// The bytes stored by `insert_value` for `key`
+    pub fn get_value<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Result<Option<&[u8]>, BTreeError> {
+        let key = key.as_ref();
+        Ok(unsafe { &*self.try_leaf_for(key)? }.get_value(key))
+    }
 
-        let last_ptr = branch
-            .header
-            .pointer
-            .expect("Invalid Branch Layout")
-            .as_ptr();
+    // the descents below only hold on to a branch until they know its child, so an allocator may
+    // let go of it while they read the next one
+    fn try_leaf_for(&self, key: &[u8]) -> Result<*mut SlottedLeaf<T, P>, BTreeError> {
+        self.try_descend(|branch| branch.try_child_at(branch.get_upper_bound(key)))
+    }
 
-        if height == 1 {
-            for node in nodes {
-                let (_, ptr) = branch.data.get_heap_entry(&branch.header, node);
-                let _boxed = unsafe { Box::from_raw(ptr as *mut SlottedLeaf<T>) };
-            }
+    fn try_descend(
+        &self,
+        mut child: impl FnMut(&SlottedBranch<T, P>) -> Result<Node, BTreeError>,
+    ) -> Result<*mut SlottedLeaf<T, P>, BTreeError> {
+        let mut node = self.root;
+
+        for _ in 0..self.height {
+            let branch = self.alloc.try_page(node)? as *mut SlottedBranch<T, P>;
+            node = child(unsafe { &*branch })?;
+        }
 
-            let _boxed = unsafe { Box::from_raw(last_ptr as *mut SlottedLeaf<T>) };
+        Ok(self.alloc.try_page(node)? as *mut SlottedLeaf<T, P>)
+    }
+
+    pub(crate) fn try_first_leaf(&self) -> Result<*mut SlottedLeaf<T, P>, BTreeError> {
+        self.try_descend(|branch| branch.try_child_at(0))
+    }
+
+    pub(crate) fn try_last_leaf(&self) -> Result<*mut SlottedLeaf<T, P>, BTreeError> {
+        self.try_descend(|branch| branch.try_child_at(branch.size()))
+    }
+
+    // the slot in front of which `key` is or would be. With `after` set an existing entry for
+    // `key` is skipped
+    pub(crate) fn try_position(
+        &self,
+        key: &[u8],
+        after: bool,
+    ) -> Result<(*mut SlottedLeaf<T, P>, usize), BTreeError> {
+        let leaf_ptr = self.try_leaf_for(key)?;
+        let leaf = unsafe { &*leaf_ptr };
+        let index = leaf.get_upper_bound(key);
+
+        if after && index < leaf.size() && leaf.is_key_at(index, key) {
+            Ok((leaf_ptr, index + 1))
         } else {
-            for node in nodes {
-                let (_, ptr) = branch.data.get_heap_entry(&branch.header, node);
-                let branch_ref = unsafe { &mut *(ptr as *mut SlottedBranch<T>) };
-                BTree::drop_branch(branch_ref, height - 1);
-                let _boxed = unsafe { Box::from_raw(ptr as *mut SlottedBranch<T>) };
-            }
+            Ok((leaf_ptr, index))
+        }
+    }
+
+    /// Iterates over all entries with keys inside `range` in ascending order, or descending when
+    /// used from the back. The tree is descended once for each end, after that we follow the leaf
+    /// chain. If that fails, the range is empty and says why.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Range<'_, T, P, A> {
+        self.try_range(range).unwrap_or_else(Range::failed)
+    }
+
+    fn try_range<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<Range<'_, T, P, A>, BTreeError> {
+        let start = range.start_bound().map(AsRef::as_ref);
+        let end = range.end_bound().map(AsRef::as_ref);
+
+        let empty = match (start, end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+            _ => false,
+        };
+
+        if empty {
+            return Ok(Range::empty(
+                unsafe { &*self.try_first_leaf()? },
+                &self.alloc,
+            ));
+        }
+
+        let (front_leaf, front_index) = match start {
+            Bound::Included(key) => self.try_position(key, false)?,
+            Bound::Excluded(key) => self.try_position(key, true)?,
+            Bound::Unbounded => (self.try_first_leaf()?, 0),
+        };
+
+        // the front has to stay put while the tree is descended a second time
+        self.alloc.pin(front_leaf as Node);
+
+        let back = match end {
+            Bound::Included(key) => self.try_position(key, true),
+            Bound::Excluded(key) => self.try_position(key, false),
+            Bound::Unbounded => self
+                .try_last_leaf()
+                .map(|leaf| (leaf, unsafe { &*leaf }.size())),
+        };
+
+        self.alloc.unpin(front_leaf as Node);
+        let (back_leaf, back_index) = back?;
+
+        Ok(Range::new(
+            (unsafe { &*front_leaf }, front_index),
+            (unsafe { &*back_leaf }, back_index),
+            &self.alloc,
+        ))
+    }
+
+    /// Iterates over all entries whose keys start with `prefix`. The first candidate is found by
+    /// the usual descent, which skips over most slots by their `first_bytes` hint alone.
+    pub fn prefix<K: AsRef<[u8]> + ?Sized>(&self, prefix: &K) -> Prefix<'_, T, P, A> {
+        let prefix = prefix.as_ref();
+        Prefix::new(self.range(prefix..), prefix.to_owned())
+    }
+
+    /// A cursor that starts out unpositioned. Call one of the `seek` methods before reading.
+    pub fn cursor(&mut self) -> Cursor<'_, T, P, A> {
+        Cursor::new(self)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, P, A> {
+        Iter::new(self.range::<&[u8], RangeFull>(..))
+    }
+
+    pub fn keys(&self) -> Keys<'_, T, P, A> {
+        Keys::new(self.iter())
+    }
+
+    pub fn values(&self) -> Values<'_, T, P, A> {
+        Values::new(self.iter())
+    }
+
+    pub fn remove<K: AsRef<[u8]> + ?Sized>(&mut self, key: &K) -> Option<*mut T> {
+        self.remove_entry(key.as_ref())
+    }
+
+    /// Removes `key` and returns the bytes stored for it by `insert_value`
+    pub fn remove_value<K: AsRef<[u8]> + ?Sized>(&mut self, key: &K) -> Option<Vec<u8>> {
+        self.remove_entry(key.as_ref())
+    }
+
+    // a tree that can't be descended to `key` is left as it is, like one without it
+    fn remove_entry<V: FromValue>(&mut self, key: &[u8]) -> Option<V> {
+        self.alloc.evict();
+        let root = self.alloc.try_page(self.root).ok()?;
+
+        if self.height == 0 {
+            let leaf = unsafe { &mut *(root as *mut SlottedLeaf<T, P>) };
+            return leaf.remove(key, &self.alloc);
+        }
+
+        let branch = unsafe { &mut *(root as *mut SlottedBranch<T, P>) };
+        let res = branch.remove(key, self.height, &self.alloc);
+
+        self.shrink();
+        res
+    }
+
+    fn shrink(&mut self) {
+        // merges below may have left the root with a single child, which then becomes the new root
+        while self.height > 0 {
+            let branch = unsafe { &*Self::branch(&self.alloc, self.root) };
 
-            let branch_ref = unsafe { &mut *(last_ptr as *mut SlottedBranch<T>) };
-            BTree::drop_branch(branch_ref, height - 1);
-            let _boxed = unsafe { Box::from_raw(last_ptr as *mut SlottedBranch<T>) };
+            let (0, Ok(child)) = (branch.size(), branch.try_child_at(0)) else {
+                break;
+            };
+
+            unsafe { self.alloc.free_node(self.root as *mut SlottedBranch<T, P>) };
+
+            self.root = child;
+            self.height -= 1;
+        }
+    }
+
+    // frees `node` and everything below it, overflow pages included
+    fn drop_node(alloc: &A, node: Node, height: usize) {
+        if height == 0 {
+            unsafe { &*Self::leaf(alloc, node) }.release_keys(alloc);
+            unsafe { alloc.free_node(node as *mut SlottedLeaf<T, P>) };
+        } else {
+            Self::drop_branch(alloc, unsafe { &*Self::branch(alloc, node) }, height);
+            unsafe { &*Self::branch(alloc, node) }.release_keys(alloc);
+            unsafe { alloc.free_node(node as *mut SlottedBranch<T, P>) };
+        }
+    }
+
+    fn drop_branch(alloc: &A, branch: &SlottedBranch<T, P>, height: usize) {
+        for index in 0..=branch.size() {
+            Self::drop_node(alloc, branch.child_at(index), height - 1);
         }
     }
 
@@ -139,15 +839,120 @@ impl<T: Debug> BTree<T> {
         self.height
     }
 
-    fn count_branch(branch: &SlottedBranch<T>, height: usize) -> usize {
+    /// How many split points on either side of the middle of a full page are looked at for the
+    /// shortest separator. Wider windows make for shorter separators and so more children per
+    /// branch, at the cost of less evenly filled pages. 0 always splits in the middle.
+    pub fn set_split_window(&mut self, window: usize) {
+        self.split_window = window;
+    }
+
+    pub fn split_window(&self) -> usize {
+        self.split_window
+    }
+
+    /// Where the pages of this tree come from
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Counts the children of every branch
+    pub fn fan_out(&self) -> FanOut {
+        let mut fan_out = FanOut {
+            branches: 0,
+            children: 0,
+            min: usize::MAX,
+            max: 0,
+        };
+
+        if self.height > 0 {
+            let branch = unsafe { &*Self::branch(&self.alloc, self.root) };
+            Self::fan_out_branch(&self.alloc, branch, self.height, &mut fan_out);
+        }
+
+        fan_out.min = fan_out.min.min(fan_out.max);
+        fan_out
+    }
+
+    fn fan_out_branch(
+        alloc: &A,
+        branch: &SlottedBranch<T, P>,
+        height: usize,
+        fan_out: &mut FanOut,
+    ) {
+        let children = branch.size() + 1;
+
+        fan_out.branches += 1;
+        fan_out.children += children;
+        fan_out.min = fan_out.min.min(children);
+        fan_out.max = fan_out.max.max(children);
+
+        if height == 1 {
+            return;
+        }
+
+        for index in 0..children {
+            let child = unsafe { &*Self::branch(alloc, branch.child_at(index)) };
+            Self::fan_out_branch(alloc, child, height - 1, fan_out);
+        }
+    }
+
+    pub(crate) fn root(&self) -> Node {
+        self.root
+    }
+
+    /// Walks the whole tree and makes sure the fences of every node match the separators its
+    /// parent routes by, and that its keys lie within them
+    #[cfg(test)]
+    pub(crate) fn check_fences(&self) {
+        Self::check_node_fences(&self.alloc, self.root, self.height, &[], None);
+    }
+
+    #[cfg(test)]
+    fn check_node_fences(alloc: &A, node: Node, height: usize, lower: &[u8], upper: Option<&[u8]>) {
+        // long separators are recorded cut short
+        let (fence_lower, fence_upper) = crate::flex::truncate_fences(lower, upper);
+        let fences = (fence_lower, fence_upper.as_deref());
+
+        if height == 0 {
+            let leaf = unsafe { &*Self::leaf(alloc, node) };
+            assert_eq!((leaf.lower_fence(), leaf.upper_fence()), fences);
+            leaf.check_fences();
+            return;
+        }
+
+        let branch = unsafe { &*Self::branch(alloc, node) };
+        assert_eq!((branch.lower_fence(), branch.upper_fence()), fences);
+        branch.check_fences();
+
+        for index in 0..=branch.size() {
+            let child_lower = match index {
+                0 => Cow::Borrowed(lower),
+                _ => branch.key_at(index - 1),
+            };
+            let child_upper = match index == branch.size() {
+                true => upper.map(Cow::Borrowed),
+                false => Some(branch.key_at(index)),
+            };
+
+            Self::check_node_fences(
+                alloc,
+                branch.child_at(index),
+                height - 1,
+                &child_lower,
+                child_upper.as_deref(),
+            );
+        }
+    }
+
+    fn count_branch(alloc: &A, branch: &SlottedBranch<T, P>, height: usize) -> usize {
         if height == 1 {
             return branch.size() + 1;
         }
 
         let mut cnt = 0;
         for index in 0..branch.size() + 1 {
-            let branch = unsafe { &*(branch.child_at(index) as *mut SlottedBranch<T>) };
-            cnt += BTree::count_branch(branch, height - 1);
+            let branch = unsafe { &*Self::branch(alloc, branch.child_at(index)) };
+            cnt += Self::count_branch(alloc, branch, height - 1);
         }
 
         return cnt;
@@ -158,85 +963,70 @@ impl<T: Debug> BTree<T> {
             return 1;
         }
 
-        let branch = unsafe { &*(self.root as *mut SlottedBranch<T>) };
-        return BTree::count_branch(branch, self.height);
+        let branch = unsafe { &*Self::branch(&self.alloc, self.root) };
+        return Self::count_branch(&self.alloc, branch, self.height);
     }
 }
 
-impl<T: Debug> Drop for BTree<T> {
+impl<T: Debug, const P: usize, A: PageAllocator> Drop for BTree<T, P, A> {
     fn drop(&mut self) {
-        if self.height == 0 {
-            // just drop in place, leaves can clean up themselves
-            let _boxed = unsafe { Box::from_raw(self.root as *mut SlottedLeaf<T>) };
-        } else {
-            let branch_ref = unsafe { &mut *(self.root as *mut SlottedBranch<T>) };
-            BTree::drop_branch(branch_ref, self.height);
-            let _boxed = unsafe { Box::from_raw(self.root as *mut SlottedBranch<T>) };
+        if A::BULK_FREE {
+            // the pages go away with the allocator, overflow pages included
+            return;
         }
+
+        Self::drop_node(&self.alloc, self.root, self.height);
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> IntoIterator for &'a BTree<T, P, A> {
+    type Item = (Cow<'a, [u8]>, *mut T);
+    type IntoIter = Iter<'a, T, P, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
-fn serialize_branch<T: Debug>(
-    branch: &SlottedBranch<T>,
+fn serialize_branch<T: Debug, const P: usize, A: PageAllocator>(
+    alloc: &A,
+    node: Node,
     height: usize,
     leaves: &mut String,
     branches: &mut String,
 ) {
-    branches.push_str(&branch.print());
+    let branch = unsafe { &*(alloc.page(node) as *const SlottedBranch<T, P>) };
+    branches.push_str(&branch.print(node));
 
-    if height == 1 {
-        let (nodes, _) = branch.data.interpret(&branch.header);
+    for index in 0..=branch.size() {
+        let child = branch.child_at(index);
 
-        for node in nodes {
-            let (_, ptr) = branch.data.get_heap_entry(&branch.header, node);
-            let leaf = unsafe { &*(ptr as *mut SlottedLeaf<T>) };
-            leaves.push_str(&leaf.print());
+        if height == 1 {
+            let leaf = unsafe { &*(alloc.page(child) as *const SlottedLeaf<T, P>) };
+            leaves.push_str(&leaf.print(child));
+        } else {
+            serialize_branch::<T, P, A>(alloc, child, height - 1, leaves, branches);
         }
-
-        let leaf = unsafe {
-            &*(branch
-                .header
-                .pointer
-                .expect("Invalid Branch Layout")
-                .as_ptr() as *mut SlottedLeaf<T>)
-        };
-
-        leaves.push_str(&leaf.print());
-
-        return;
-    }
-
-    let (nodes, _) = branch.data.interpret(&branch.header);
-
-    for node in nodes {
-        let (_, ptr) = branch.data.get_heap_entry(&branch.header, node);
-        let new_branch = unsafe { &*(ptr as *mut SlottedBranch<T>) };
-        serialize_branch(new_branch, height - 1, leaves, branches);
     }
-
-    let last_branch = unsafe {
-        &*(branch
-            .header
-            .pointer
-            .expect("Invalid Branch Layout")
-            .as_ptr() as *mut SlottedBranch<T>)
-    };
-
-    serialize_branch(last_branch, height - 1, leaves, branches);
 }
 
-impl<T: Debug> Graphviz for BTree<T> {
+impl<T: Debug, const P: usize, A: PageAllocator> Graphviz for BTree<T, P, A> {
     fn serialize(&self, f: &mut dyn io::Write) -> io::Result<()> {
         if self.height == 0 {
-            let leaf = unsafe { &mut *(self.root as *mut SlottedLeaf<T>) };
-            write!(f, "{}", leaf.print())?;
+            let leaf = unsafe { &*Self::leaf(&self.alloc, self.root) };
+            write!(f, "{}", leaf.print(self.root))?;
             return Ok(());
         }
 
-        let branch = unsafe { &mut *(self.root as *mut SlottedBranch<T>) };
         let mut branches = String::new();
         let mut leaves = String::new();
-        serialize_branch(branch, self.height, &mut leaves, &mut branches);
+        serialize_branch::<T, P, A>(
+            &self.alloc,
+            self.root,
+            self.height,
+            &mut leaves,
+            &mut branches,
+        );
 
         // DOT has a *few* problems trying to draw our tree. If I put rank=same in there, the arrows
         // between the leaves don't draw, but the alternative is worse. You can remove it to see
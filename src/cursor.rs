@@ -0,0 +1,132 @@
+use std::{borrow::Cow, fmt::Debug};
+
+use crate::{
+    btree::BTree,
+    page_alloc::{Heap, PageAllocator},
+    slotted_leaf::SlottedLeaf,
+    PAGE_SIZE,
+};
+
+/// A position on an entry of a tree that can be moved in both directions and used to update values
+/// in place. Moving only follows the leaf chain, the tree is descended for seeks alone. Running off
+/// either end leaves the cursor unpositioned until the next seek. Created by
+/// [`BTree::cursor`](crate::btree::BTree::cursor).
+pub struct Cursor<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    tree: &'a mut BTree<T, P, A>,
+    position: Option<(*mut SlottedLeaf<T, P>, usize)>,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Cursor<'a, T, P, A> {
+    pub(crate) fn new(tree: &'a mut BTree<T, P, A>) -> Self {
+        Self {
+            tree,
+            position: None,
+        }
+    }
+
+    /// Moves to the first entry with a key greater than or equal to `key`. Returns whether there
+    /// is one. Like running off an end, a tree that can't be descended leaves the cursor
+    /// unpositioned.
+    pub fn seek<K: AsRef<[u8]> + ?Sized>(&mut self, key: &K) -> bool {
+        match self.tree.try_position(key.as_ref(), false) {
+            Ok((leaf, index)) => self.settle_forward(leaf, index),
+            Err(_) => self.unposition(),
+        }
+    }
+
+    pub fn seek_first(&mut self) -> bool {
+        match self.tree.try_first_leaf() {
+            Ok(leaf) => self.settle_forward(leaf, 0),
+            Err(_) => self.unposition(),
+        }
+    }
+
+    pub fn seek_last(&mut self) -> bool {
+        match self.tree.try_last_leaf() {
+            Ok(leaf) => self.settle_backward(leaf, unsafe { &*leaf }.size()),
+            Err(_) => self.unposition(),
+        }
+    }
+
+    // a cursor does not yield anything, so it's not an Iterator despite the name
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> bool {
+        let Some((leaf, index)) = self.position else {
+            return false;
+        };
+
+        self.settle_forward(leaf, index + 1)
+    }
+
+    pub fn prev(&mut self) -> bool {
+        let Some((leaf, index)) = self.position else {
+            return false;
+        };
+
+        self.settle_backward(leaf, index)
+    }
+
+    pub fn key(&self) -> Option<Cow<'_, [u8]>> {
+        let (leaf, index) = self.position?;
+        Some(unsafe { &*leaf }.key_at(index))
+    }
+
+    /// The pointer stored for the current entry. None if the entry holds a value stored inline
+    pub fn value(&self) -> Option<*mut T> {
+        let (leaf, index) = self.position?;
+        unsafe { &*leaf }.value_at(index)
+    }
+
+    /// Overwrites the value of the current entry, returning the old one. An entry with a value
+    /// stored inline is left alone and None is returned, just like without a current entry
+    pub fn set_value(&mut self, value: *mut T) -> Option<*mut T> {
+        let (leaf, index) = self.position?;
+        let leaf = unsafe { &mut *leaf };
+        let old = leaf.swap_value_at(index, value)?;
+        leaf.mark_dirty(self.tree.allocator());
+        Some(old)
+    }
+
+    // lands on the first entry at or after `index`, skipping over empty leaves
+    fn settle_forward(&mut self, mut leaf: *mut SlottedLeaf<T, P>, mut index: usize) -> bool {
+        while index >= unsafe { &*leaf }.size() {
+            let Some(next) = unsafe { &*leaf }.next_leaf() else {
+                return self.unposition();
+            };
+
+            let Ok(next) = self.tree.allocator().try_page(next.as_ptr()) else {
+                return self.unposition();
+            };
+
+            leaf = next as *mut SlottedLeaf<T, P>;
+            index = 0;
+        }
+
+        self.position = Some((leaf, index));
+        true
+    }
+
+    // lands on the last entry before `index`, skipping over empty leaves
+    fn settle_backward(&mut self, mut leaf: *mut SlottedLeaf<T, P>, mut index: usize) -> bool {
+        while index == 0 {
+            let Some(prev) = unsafe { &*leaf }.prev_leaf() else {
+                return self.unposition();
+            };
+
+            let Ok(prev) = self.tree.allocator().try_page(prev.as_ptr()) else {
+                return self.unposition();
+            };
+
+            leaf = prev as *mut SlottedLeaf<T, P>;
+            index = unsafe { &*leaf }.size();
+        }
+
+        self.position = Some((leaf, index - 1));
+        true
+    }
+
+    fn unposition(&mut self) -> bool {
+        self.position = None;
+        false
+    }
+}
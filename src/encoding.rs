@@ -0,0 +1,294 @@
+// Order preserving ("memcomparable") key encodings. Comparing two encoded keys byte by byte gives
+// the same result as comparing the original values, which is all the tree needs to store them.
+
+/// A type that can be turned into a tree key and back.
+pub trait KeyEncode: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Reads one value from the front of `input` and advances past it.
+    fn decode_key(input: &mut &[u8]) -> Option<Self>;
+
+    fn to_key(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_key(&mut out);
+        out
+    }
+
+    /// Decodes a whole key. Trailing bytes are treated as a malformed key.
+    fn from_key(mut key: &[u8]) -> Option<Self> {
+        let value = Self::decode_key(&mut key)?;
+        key.is_empty().then_some(value)
+    }
+}
+
+fn take<const N: usize>(input: &mut &[u8]) -> Option<[u8; N]> {
+    let (bytes, rest) = input.split_first_chunk::<N>()?;
+    *input = rest;
+    Some(*bytes)
+}
+
+impl KeyEncode for u64 {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Option<Self> {
+        take(input).map(u64::from_be_bytes)
+    }
+}
+
+// flipping the sign bit moves negative numbers in front of the positive ones
+impl KeyEncode for i64 {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        ((*self as u64) ^ (1 << 63)).encode_key(out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Option<Self> {
+        u64::decode_key(input).map(|bits| (bits ^ (1 << 63)) as i64)
+    }
+}
+
+// positive floats already sort like their bits, once the sign bit is set to put them in front of
+// the negative ones. Negative floats sort in reverse, so all their bits are flipped. This gives
+// -NaN < -inf < ... < -0.0 < 0.0 < ... < inf < NaN
+impl KeyEncode for f64 {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        let bits = self.to_bits();
+
+        let bits = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits | (1 << 63)
+        };
+
+        bits.encode_key(out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Option<Self> {
+        let bits = u64::decode_key(input)?;
+
+        let bits = if bits >> 63 == 1 {
+            bits & !(1 << 63)
+        } else {
+            !bits
+        };
+
+        Some(f64::from_bits(bits))
+    }
+}
+
+// byte strings are terminated by 0x00 0x01 so they can be followed by other fields. Zero bytes
+// inside are escaped as 0x00 0xFF, which keeps "a" < "a\0" < "ab"
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+impl KeyEncode for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        for &byte in self {
+            out.push(byte);
+
+            if byte == ESCAPE {
+                out.push(ESCAPED_ZERO);
+            }
+        }
+
+        out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Option<Self> {
+        let mut value = vec![];
+
+        loop {
+            let [byte] = take(input)?;
+
+            if byte != ESCAPE {
+                value.push(byte);
+                continue;
+            }
+
+            match take(input)? {
+                [ESCAPED_ZERO] => value.push(ESCAPE),
+                [TERMINATOR] => return Some(value),
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl KeyEncode for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        // same layout as Vec<u8>, minus the copy
+        for &byte in self.as_bytes() {
+            out.push(byte);
+
+            if byte == ESCAPE {
+                out.push(ESCAPED_ZERO);
+            }
+        }
+
+        out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(Vec::decode_key(input)?).ok()
+    }
+}
+
+// tuples are just their fields back to back. Every field encoding is self delimiting, so an earlier
+// field decides the order before a later one is even looked at
+macro_rules! tuple_key {
+    ($($name:ident),+) => {
+        impl<$($name: KeyEncode),+> KeyEncode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_key(out);)+
+            }
+
+            fn decode_key(input: &mut &[u8]) -> Option<Self> {
+                Some(($($name::decode_key(input)?,)+))
+            }
+        }
+    };
+}
+
+tuple_key!(A);
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+tuple_key!(A, B, C, D, E);
+
+#[cfg(test)]
+mod encoding_tests {
+    use std::fmt::Debug;
+
+    use crate::btree::BTree;
+
+    use super::KeyEncode;
+
+    fn check_order<K: KeyEncode + PartialOrd + Debug>(values: &[K]) {
+        for a in values {
+            assert_eq!(K::from_key(&a.to_key()).as_ref(), Some(a));
+
+            for b in values {
+                assert_eq!(
+                    a.partial_cmp(b),
+                    Some(a.to_key().cmp(&b.to_key())),
+                    "{a:?} vs {b:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn integers() {
+        let mut unsigned = vec![0, 1, 255, 256, u64::MAX - 1, u64::MAX];
+        let mut signed = vec![i64::MIN, i64::MIN + 1, -256, -1, 0, 1, 255, i64::MAX];
+
+        unsigned.extend((0..100).map(|_| rand::random::<u64>()));
+        signed.extend((0..100).map(|_| rand::random::<i64>()));
+
+        check_order(&unsigned);
+        check_order(&signed);
+    }
+
+    #[test]
+    fn floats() {
+        let mut floats = vec![
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.5,
+            f64::MAX,
+            f64::INFINITY,
+        ];
+
+        floats.extend((0..100).map(|_| (rand::random::<f64>() - 0.5) * 1e10));
+
+        // -0.0 == 0.0, but the encoding keeps them apart
+        floats.retain(|f| *f != 0.0);
+        check_order(&floats);
+
+        assert!((-0.0f64).to_key() < 0.0f64.to_key());
+        assert!(f64::from_key(&f64::NAN.to_key()).unwrap().is_nan());
+    }
+
+    #[test]
+    fn byte_strings() {
+        let strings: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![0, 1],
+            vec![0, 255],
+            vec![1],
+            b"a".to_vec(),
+            b"a\0".to_vec(),
+            b"a\0b".to_vec(),
+            b"ab".to_vec(),
+            vec![255, 0],
+            vec![255, 255],
+        ];
+
+        check_order(&strings);
+
+        // a string followed by more fields must still sort before any longer string
+        let tuples: Vec<_> = strings.iter().map(|s| (s.clone(), u64::MAX)).collect();
+        check_order(&tuples);
+
+        assert_eq!(Vec::<u8>::from_key(&[b'a', 0, 7]), None);
+        assert_eq!(Vec::<u8>::from_key(b"a"), None);
+        assert_eq!(String::from_key(&[0xC3, 0, 1]), None);
+    }
+
+    #[test]
+    fn tuples() {
+        let mut tuples = vec![];
+
+        for tenant in ["", "acme", "acme\0", "globex"] {
+            for id in [-5i64, 0, 7] {
+                for score in [-1.0, 0.5, 2.0] {
+                    tuples.push((tenant.to_owned(), id, score));
+                }
+            }
+        }
+
+        check_order(&tuples);
+
+        assert_eq!(<(u64, u64)>::from_key(&(1u64, 2u64).to_key()), Some((1, 2)));
+        assert_eq!(<(u64, u64)>::from_key(&1u64.to_key()), None);
+    }
+
+    #[test]
+    fn typed_index() {
+        let mut tree: BTree<()> = BTree::new();
+
+        for i in -5_000i64..5_000 {
+            tree.insert(&(i % 7, i).to_key(), std::ptr::null_mut())
+                .unwrap();
+        }
+
+        let keys: Vec<_> = tree
+            .keys()
+            .map(|key| <(i64, i64)>::from_key(&key).unwrap())
+            .collect();
+
+        let mut expected: Vec<_> = (-5_000i64..5_000).map(|i| (i % 7, i)).collect();
+        expected.sort();
+
+        assert_eq!(keys, expected);
+
+        // everything in group -3, found through a prefix of the composite key
+        let group = (-3i64).to_key();
+        assert!(tree
+            .prefix(&group)
+            .map(|(key, _)| <(i64, i64)>::from_key(&key).unwrap())
+            .eq(expected.iter().copied().filter(|(group, _)| *group == -3)));
+    }
+}
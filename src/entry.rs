@@ -0,0 +1,191 @@
+use std::{fmt::Debug, marker::PhantomData};
+
+use crate::{
+    btree::{BTree, BTreeError, InsertResultIntern, Node},
+    flex::ptr_to_bytes,
+    page_alloc::{Heap, PageAllocator},
+    slotted_branch::SlottedBranch,
+    slotted_leaf::SlottedLeaf,
+    PAGE_SIZE,
+};
+
+/// A view into a single slot of a tree, which may or may not be taken. Created by
+/// [`BTree::entry`](crate::btree::BTree::entry).
+pub enum Entry<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    Occupied(OccupiedEntry<'a, T, P, A>),
+    Vacant(VacantEntry<'a, T, P, A>),
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Entry<'a, T, P, A> {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns the stored value, inserting `value` first if there is none.
+    pub fn or_insert(self, value: *mut T) -> Result<*mut T, BTreeError> {
+        match self {
+            Entry::Occupied(entry) => entry.try_get(),
+            Entry::Vacant(entry) => entry.insert(value),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> *mut T>(self, default: F) -> Result<*mut T, BTreeError> {
+        match self {
+            Entry::Occupied(entry) => entry.try_get(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Lets `f` change an existing value, either in place behind the pointer or by replacing the
+    /// pointer itself. A value stored inline is left alone.
+    pub fn and_modify<F: FnOnce(&mut *mut T)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                if let Some(mut value) = entry.get() {
+                    f(&mut value);
+                    let _ = entry.insert(value);
+                }
+
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+
+    /// Stores `value` no matter what, returning the value it replaced.
+    pub fn insert(self, value: *mut T) -> Result<Option<*mut T>, BTreeError> {
+        match self {
+            Entry::Occupied(mut entry) => entry.insert(value).map(Some),
+            Entry::Vacant(entry) => {
+                entry.insert(value)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+pub struct OccupiedEntry<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    leaf: *mut SlottedLeaf<T, P>,
+    index: usize,
+    key: Vec<u8>,
+    // told about the leaf changing when the value is swapped
+    alloc: &'a A,
+    boo: PhantomData<&'a mut BTree<T, P, A>>,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> OccupiedEntry<'a, T, P, A> {
+    pub(crate) fn new(
+        leaf: *mut SlottedLeaf<T, P>,
+        index: usize,
+        key: Vec<u8>,
+        alloc: &'a A,
+    ) -> Self {
+        Self {
+            leaf,
+            index,
+            key,
+            alloc,
+            boo: PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The stored pointer. None if the entry holds a value stored inline instead
+    pub fn get(&self) -> Option<*mut T> {
+        unsafe { &*self.leaf }.value_at(self.index)
+    }
+
+    fn try_get(&self) -> Result<*mut T, BTreeError> {
+        self.get().ok_or_else(|| BTreeError::ValueSize {
+            len: unsafe { &*self.leaf }.value_bytes_at(self.index).len(),
+        })
+    }
+
+    /// Overwrites the value and returns the old one. A value stored inline can't be swapped for a
+    /// pointer in place, the entry is left as it is then
+    pub fn insert(&mut self, value: *mut T) -> Result<*mut T, BTreeError> {
+        let old = self.try_get()?;
+        let leaf = unsafe { &mut *self.leaf };
+        leaf.swap_value_at(self.index, value);
+        leaf.mark_dirty(self.alloc);
+        Ok(old)
+    }
+}
+
+pub struct VacantEntry<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    tree: &'a mut BTree<T, P, A>,
+    key: Vec<u8>,
+    // every branch we passed on the way down and the slot we took in it, root first
+    path: Vec<(*mut SlottedBranch<T, P>, usize)>,
+    leaf: *mut SlottedLeaf<T, P>,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> VacantEntry<'a, T, P, A> {
+    pub(crate) fn new(
+        tree: &'a mut BTree<T, P, A>,
+        key: Vec<u8>,
+        path: Vec<(*mut SlottedBranch<T, P>, usize)>,
+        leaf: *mut SlottedLeaf<T, P>,
+    ) -> Self {
+        Self {
+            tree,
+            key,
+            path,
+            leaf,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Inserts into the leaf we found on the way down. If it splits, the separators are handed up
+    /// along the remembered path instead of descending again.
+    pub fn insert(self, value: *mut T) -> Result<*mut T, BTreeError> {
+        self.tree.check_pages(&self.key)?;
+
+        let window = self.tree.split_window();
+        let alloc = self.tree.allocator();
+        let leaf = unsafe { &mut *self.leaf };
+        let mut res = leaf.insert_with::<Node, A>(
+            &self.key,
+            &ptr_to_bytes(value as Node),
+            false,
+            window,
+            alloc,
+        )?;
+        let mut from_leaf = true;
+
+        for &(branch, index) in self.path.iter().rev() {
+            let InsertResultIntern::Split(separator, node, _) = res else {
+                return Ok(value);
+            };
+
+            let branch = unsafe { &mut *branch };
+
+            res = if from_leaf {
+                branch.insert_leaf_at(index, &separator, node, window, alloc)?
+            } else {
+                branch.insert_at(index, &separator, node, window, alloc)?
+            };
+
+            from_leaf = false;
+        }
+
+        if let InsertResultIntern::Split(separator, node, _) = res {
+            if from_leaf {
+                self.tree.grow_leaf(&separator, node)?;
+            } else {
+                self.tree.grow_branch(&separator, node)?;
+            }
+        }
+
+        Ok(value)
+    }
+}
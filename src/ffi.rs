@@ -1,12 +1,14 @@
-use std::usize;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    usize,
+};
 
-use crate::btree::BTree;
+use crate::btree::{BTree, BTreeError, InsertResult};
 
 type FfiBTree = *mut BTree<()>;
 
-fn get_rust_string(string: *const u8, len: usize) -> &'static str {
-    let slice = unsafe { std::slice::from_raw_parts(string, len) };
-    unsafe { std::str::from_utf8_unchecked(slice) }
+fn get_key(string: *const u8, len: usize) -> &'static [u8] {
+    unsafe { std::slice::from_raw_parts(string, len) }
 }
 
 #[no_mangle]
@@ -21,21 +23,164 @@ pub extern "C" fn ffi_btree_drop(tree: FfiBTree) {
     let _ = unsafe { Box::from_raw(tree) };
 }
 
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub enum FfiInsertResult {
+    Inserted = 0,
+    Replaced = 1,
+    Present = 2,
+    InvalidLayout = -1,
+    NoSeparator = -2,
+    ValueTooLarge = -3,
+    UnhandledSplit = -4,
+    Panicked = -5,
+    OutOfPages = -6,
+    ValueSize = -7,
+    PageRead = -8,
+}
+
+impl From<BTreeError> for FfiInsertResult {
+    fn from(err: BTreeError) -> Self {
+        match err {
+            BTreeError::InvalidLayout => FfiInsertResult::InvalidLayout,
+            BTreeError::NoSeparator => FfiInsertResult::NoSeparator,
+            BTreeError::ValueTooLarge { .. } => FfiInsertResult::ValueTooLarge,
+            BTreeError::UnhandledSplit => FfiInsertResult::UnhandledSplit,
+            BTreeError::OutOfPages => FfiInsertResult::OutOfPages,
+            BTreeError::ValueSize { .. } => FfiInsertResult::ValueSize,
+            BTreeError::PageRead(_) => FfiInsertResult::PageRead,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub enum FfiGetResult {
+    Found = 0,
+    Missing = 1,
+    InvalidLayout = -1,
+    Panicked = -5,
+}
+
+// unwinding across `extern "C"` is undefined behaviour. Anything that still panics is turned into a
+// status code instead
+fn catch<R>(f: impl FnOnce() -> R) -> Option<R> {
+    panic::catch_unwind(AssertUnwindSafe(f)).ok()
+}
+
+// hands the value that was already stored to the caller, if they asked for it
+fn report_insert(
+    res: Option<Result<InsertResult, BTreeError>>,
+    existing: *mut *mut (),
+) -> FfiInsertResult {
+    let (status, value) = match res {
+        None => return FfiInsertResult::Panicked,
+        Some(Err(err)) => return err.into(),
+        Some(Ok(InsertResult::Inserted)) => return FfiInsertResult::Inserted,
+        Some(Ok(InsertResult::Replaced(old))) => (FfiInsertResult::Replaced, old),
+        Some(Ok(InsertResult::Present(old))) => (FfiInsertResult::Present, old),
+    };
+
+    if !existing.is_null() {
+        unsafe { *existing = value };
+    }
+
+    status
+}
+
+/// Overwrites an existing entry. Errors are dropped, use `ffi_btree_insert_with_result` to see
+/// them.
+///
+/// # Safety
+///
+/// `tree` has to come from `ffi_btree_new`, and `string` has to point to `len` readable bytes.
 #[no_mangle]
-pub extern "C" fn ffi_btree_insert(tree: FfiBTree, string: *const u8, len: usize, value: *mut ()) {
-    let key = get_rust_string(string, len);
+pub unsafe extern "C" fn ffi_btree_insert(
+    tree: FfiBTree,
+    string: *const u8,
+    len: usize,
+    value: *mut (),
+) {
+    unsafe { ffi_btree_insert_with_result(tree, string, len, value, std::ptr::null_mut()) };
+}
+
+/// Overwrites an existing entry. The previous value is written to `replaced` unless it is null.
+///
+/// # Safety
+///
+/// Same as for `ffi_btree_insert`. `replaced` has to be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn ffi_btree_insert_with_result(
+    tree: FfiBTree,
+    string: *const u8,
+    len: usize,
+    value: *mut (),
+    replaced: *mut *mut (),
+) -> FfiInsertResult {
+    let key = get_key(string, len);
 
     let tree = unsafe { &mut *tree };
-    let _res = tree.insert(key, value);
+    report_insert(catch(|| tree.insert(key, value)), replaced)
 }
 
+/// Keeps an existing entry. Its value is written to `present` unless it is null.
+///
+/// # Safety
+///
+/// Same as for `ffi_btree_insert`. `present` has to be null or writable.
 #[no_mangle]
-pub extern "C" fn ffi_btree_get(tree: FfiBTree, string: *const u8, len: usize) -> *mut () {
-    let key = get_rust_string(string, len);
+pub unsafe extern "C" fn ffi_btree_insert_if_absent(
+    tree: FfiBTree,
+    string: *const u8,
+    len: usize,
+    value: *mut (),
+    present: *mut *mut (),
+) -> FfiInsertResult {
+    let key = get_key(string, len);
 
     let tree = unsafe { &mut *tree };
-    match tree.get(key) {
-        Some(p) => p,
-        None => std::ptr::null_mut(),
+    report_insert(catch(|| tree.insert_if_absent(key, value)), present)
+}
+
+/// The value stored under the key, or null if it is missing or could not be looked up. Use
+/// `ffi_btree_get_with_status` to tell these apart.
+///
+/// # Safety
+///
+/// Same as for `ffi_btree_insert`.
+#[no_mangle]
+pub unsafe extern "C" fn ffi_btree_get(tree: FfiBTree, string: *const u8, len: usize) -> *mut () {
+    let mut value = std::ptr::null_mut();
+    unsafe { ffi_btree_get_with_status(tree, string, len, &mut value) };
+
+    value
+}
+
+/// Writes the value stored under the key to `value`. It is left untouched if the key is missing.
+///
+/// # Safety
+///
+/// Same as for `ffi_btree_insert`. `value` has to be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn ffi_btree_get_with_status(
+    tree: FfiBTree,
+    string: *const u8,
+    len: usize,
+    value: *mut *mut (),
+) -> FfiGetResult {
+    let key = get_key(string, len);
+
+    let tree = unsafe { &mut *tree };
+    match catch(|| tree.get(key)) {
+        None => FfiGetResult::Panicked,
+        Some(Err(_)) => FfiGetResult::InvalidLayout,
+        Some(Ok(None)) => FfiGetResult::Missing,
+        Some(Ok(Some(found))) => {
+            if !value.is_null() {
+                unsafe { *value = found };
+            }
+
+            FfiGetResult::Found
+        }
     }
 }
@@ -1,13 +1,45 @@
-use crate::btree::Node;
-use crate::PTR_SIZE;
+use crate::btree::{BTreeError, Node};
+use crate::page_alloc::PageAllocator;
+use crate::{PAGE_SIZE, PTR_SIZE};
 use bytemuck::{cast_slice, cast_slice_mut};
 
 use bytemuck::{Pod, Zeroable};
 
-use crate::PAGE_SIZE;
-use std::{fmt::Debug, ptr::NonNull};
+use std::{alloc::Layout, borrow::Cow, cmp::Ordering, fmt::Debug, iter, ptr::NonNull, slice};
 
-pub const DATA_LEN: usize = PAGE_SIZE - size_of::<FlexHead>();
+/// The largest page size. Offsets into a page are `u16`, so bigger pages could not be addressed
+pub const MAX_PAGE_SIZE: usize = 1 << 16;
+
+/// Bytes at the start of every page taken up by its `FlexHead`
+pub const HEAD_LEN: usize = size_of::<FlexHead>();
+
+// how many split points on either side of the middle of a page are looked at for the shortest
+// separator, unless the tree is told otherwise
+pub const DEFAULT_SPLIT_WINDOW: usize = 1;
+
+// leaf entries keep their value inline in front of the key: [value len][value][key]. Pointers are
+// just values that happen to be PTR_SIZE long
+pub const VALUE_LEN_SIZE: usize = size_of::<u16>();
+
+// with the `simd-hints` layout every slot's `first_bytes` is also kept in a contiguous array right
+// behind the slot array: [slots][hints][free space][heap]. That costs 4 bytes per entry, but lets
+// the hints be compared a whole vector at a time
+#[cfg(feature = "simd-hints")]
+pub const HINT_SIZE: usize = size_of::<u32>();
+#[cfg(not(feature = "simd-hints"))]
+pub const HINT_SIZE: usize = 0;
+
+// a page records the keys bounding it, its fences, at its top: [heap][lower][upper][reserved]. Each is
+// [len][key]. Fences longer than MAX_INLINE_FENCE are cut short, see `truncate_fences`, so they
+// never take up more than MAX_FENCES_LEN of the page. A missing upper fence has a length of
+// NO_FENCE, a page unbounded on both sides has an empty lower and a missing upper fence
+pub const MAX_INLINE_FENCE: usize = 32;
+const FENCE_LEN_SIZE: usize = size_of::<u16>();
+const NO_FENCE: u16 = u16::MAX;
+pub const MAX_FENCES_LEN: usize = 2 * (FENCE_LEN_SIZE + MAX_INLINE_FENCE);
+
+/// Page bytes taken up by a slot, not counting its heap entry
+pub const SLOT_SIZE: usize = size_of::<SlotNode>() + HINT_SIZE;
 
 #[repr(C)]
 #[derive(Pod, Copy, Clone, Zeroable, Debug)]
@@ -19,6 +51,10 @@ pub struct SlotNode {
 
 impl SlotNode {
     pub fn new(start: u16, end: u16, first_bytes: u32) -> Self {
+        // u16::MAX marks the entry that is not in the page yet while a split is worked out, so no
+        // real offset may ever reach it, not even at the end of the biggest page
+        const { assert!(MAX_PAGE_SIZE - HEAD_LEN < u16::MAX as usize) };
+
         SlotNode {
             start,
             end,
@@ -27,88 +63,270 @@ impl SlotNode {
     }
 }
 
+// the first bytes of every page, laid out like this in memory and in a data file alike. The slots
+// and the heap follow right behind it
+#[repr(C)]
 #[derive(Debug)]
 pub struct FlexHead {
     pub node_count: u16,
     pub key_pos: u16,
+    // start of the fence records at the top of the page
+    pub fences: u16,
+    // how many leading bytes of the lower fence every key in the page shares. Only leaves leave
+    // them out of their keys, branches keep this at 0
+    pub prefix_len: u16,
     pub pointer: Option<std::ptr::NonNull<()>>,
 }
 
-impl FlexHead {
-    pub fn new(pointer: Option<NonNull<()>>) -> Self {
-        Self {
+/// A whole page of `P` bytes: its header, then the slots and the heap. Offsets are counted from the
+/// end of the header and are `u16`, so pages can be at most 64 KiB
+#[repr(C, align(8))]
+pub struct Flex<const P: usize = PAGE_SIZE> {
+    raw: [u8; P],
+}
+
+impl<const P: usize> Flex<P> {
+    /// Bytes of the page left for slots and heap once the header is taken out
+    pub const DATA_LEN: usize = P - HEAD_LEN;
+
+    // nodes whose live slots and heap entries take up less than this get merged with or refilled
+    // from a sibling after a removal
+    pub const UNDERFULL_BYTES: usize = Self::DATA_LEN / 4;
+
+    // keys longer than this are moved out of the page into a chain of overflow pages, which come
+    // from the allocator of the tree like any other page. What stays behind is a stub of
+    // MAX_INLINE_KEY + 1 bytes: [first key bytes][key len][first overflow page]. So the length of
+    // the key area alone tells the two apart. Whether a key goes into overflow pages depends on its
+    // whole length, never on the prefix of its page, so the stub can be moved between pages as it
+    // is. The first bytes decide most comparisons without following the chain
+    pub const MAX_INLINE_KEY: usize = Self::DATA_LEN / 16;
+    const OVERFLOW_STUB_LEN: usize = Self::MAX_INLINE_KEY + 1;
+    const OVERFLOW_PREFIX_LEN: usize = Self::OVERFLOW_STUB_LEN - 2 * size_of::<usize>();
+
+    /// An empty, unbounded page. `pointer` is the rightmost child of a branch or the right sibling
+    /// of a leaf. The last `reserved` bytes are left to the page type, see `reserved`
+    pub fn new(pointer: Option<NonNull<()>>, reserved: usize) -> Self {
+        // the fences alone can take up to MAX_FENCES_LEN bytes, so small pages would not fit any key
+        const {
+            assert!(
+                P >= 512 && P <= MAX_PAGE_SIZE,
+                "Pages have to be between 512 bytes and 64 KiB"
+            )
+        };
+
+        let mut page = Self { raw: [0; P] };
+        *page.head_mut() = FlexHead {
             node_count: 0,
-            key_pos: DATA_LEN as u16,
+            key_pos: 0,
+            fences: 0,
+            prefix_len: 0,
             pointer,
-        }
+        };
+
+        page.write_fences(Self::DATA_LEN - reserved, &[], None);
+        page
     }
-}
 
-pub struct Flex {
-    raw: [u8; PAGE_SIZE - size_of::<FlexHead>()],
-}
+    pub fn head(&self) -> &FlexHead {
+        // SAFETY: the page is aligned for the header, and any bytes make a valid FlexHead
+        unsafe { &*(self.raw.as_ptr() as *const FlexHead) }
+    }
+
+    pub fn head_mut(&mut self) -> &mut FlexHead {
+        // SAFETY: see `head`
+        unsafe { &mut *(self.raw.as_mut_ptr() as *mut FlexHead) }
+    }
+
+    /// The bytes behind the header, which all offsets in the page point into
+    fn body(&self) -> &[u8] {
+        &self.raw[HEAD_LEN..]
+    }
 
-impl Flex {
-    pub fn new() -> Self {
-        Self { raw: [0; DATA_LEN] }
+    fn body_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[HEAD_LEN..]
     }
 
     pub fn get_raw(&self, index: usize) -> &u8 {
         return &self.raw[index];
     }
 
-    pub fn interpret(&self, header: &FlexHead) -> (&[SlotNode], &[u8]) {
-        let split = header.node_count as usize * size_of::<SlotNode>();
+    pub fn interpret(&self) -> (&[SlotNode], &[u8]) {
+        let count = self.head().node_count as usize;
 
-        let (nodes, data) = self.raw.split_at(split);
-        (cast_slice(nodes), data)
+        let (nodes, data) = self.body().split_at(count * SLOT_SIZE);
+        (cast_slice(&nodes[..count * size_of::<SlotNode>()]), data)
     }
 
-    pub fn interpret_mut(&mut self, header: &FlexHead) -> (&mut [SlotNode], &mut [u8]) {
-        let split = header.node_count as usize * size_of::<SlotNode>();
+    pub fn interpret_mut(&mut self) -> (&mut [SlotNode], &mut [u8]) {
+        let count = self.head().node_count as usize;
 
-        let (nodes, data) = self.raw.split_at_mut(split);
-        (cast_slice_mut(nodes), data)
+        let (nodes, data) = self.body_mut().split_at_mut(count * SLOT_SIZE);
+        (
+            cast_slice_mut(&mut nodes[..count * size_of::<SlotNode>()]),
+            data,
+        )
     }
 
-    pub fn key_at(&self, header: &FlexHead, index: usize) -> &str {
-        self.entry_at(header, index).0
+    // the contiguous copy of every slot's `first_bytes`, right behind the slot array
+    #[cfg(feature = "simd-hints")]
+    pub fn hints(&self) -> &[u32] {
+        let count = self.head().node_count as usize;
+        let start = count * size_of::<SlotNode>();
+
+        cast_slice(&self.body()[start..start + count * HINT_SIZE])
     }
 
-    pub fn key_at_overflow<'a>(
-        &'a self,
-        header: &'a FlexHead,
-        index: usize,
-        extra_slot: (&'a str, Node),
-        extra_node: &SlotNode,
-    ) -> &'a str {
-        if index == header.node_count as usize {
-            self.get_overflow_heap_entry(header, &extra_node, extra_slot)
-                .0
-        } else {
-            let node = self.interpret(header).0[index];
-            self.get_overflow_heap_entry(header, &node, extra_slot).0
+    #[cfg(feature = "simd-hints")]
+    fn hints_mut(&mut self, count: usize) -> &mut [u32] {
+        let start = count * size_of::<SlotNode>();
+
+        cast_slice_mut(&mut self.body_mut()[start..start + count * HINT_SIZE])
+    }
+
+    /// Records the keys bounding this page at its top. Must happen before the first entry is added
+    pub fn set_fences(&mut self, lower: &[u8], upper: Option<&[u8]>) {
+        debug_assert!(self.head().node_count == 0 && self.head().key_pos == self.head().fences);
+
+        self.write_fences(self.fences_end(), lower, upper);
+    }
+
+    // writes the fence records so they end at `top` and starts the heap below them
+    fn write_fences(&mut self, top: usize, lower: &[u8], upper: Option<&[u8]>) {
+        let (lower, upper) = truncate_fences(lower, upper);
+        let upper = upper.as_deref();
+
+        let upper_start = top - fence_record_len(upper);
+        let lower_start = upper_start - fence_record_len(Some(lower));
+
+        write_fence(&mut self.body_mut()[lower_start..upper_start], Some(lower));
+        write_fence(&mut self.body_mut()[upper_start..top], upper);
+
+        self.head_mut().fences = lower_start as u16;
+        self.head_mut().key_pos = lower_start as u16;
+    }
+
+    // the fence records: where the upper one starts and where both end
+    fn fence_records(&self) -> (usize, usize) {
+        let start = self.head().fences as usize;
+        let (_, lower_len) = read_fence(&self.body()[start..]);
+        let (_, upper_len) = read_fence(&self.body()[start + lower_len..]);
+
+        (start + lower_len, start + lower_len + upper_len)
+    }
+
+    fn fences_end(&self) -> usize {
+        self.fence_records().1
+    }
+
+    /// The bytes above the fences, which the page type asked `new` to keep for itself
+    pub fn reserved(&self) -> &[u8] {
+        &self.body()[self.fences_end()..]
+    }
+
+    pub fn reserved_mut(&mut self) -> &mut [u8] {
+        let end = self.fences_end();
+        &mut self.body_mut()[end..]
+    }
+
+    /// The smallest key this page may hold. Empty if it is unbounded below
+    pub fn lower_fence(&self) -> &[u8] {
+        read_fence(&self.body()[self.head().fences as usize..])
+            .0
+            .unwrap_or_default()
+    }
+
+    /// The key every key in this page is smaller than. None if it is unbounded above
+    pub fn upper_fence(&self) -> Option<&[u8]> {
+        read_fence(&self.body()[self.fence_records().0..]).0
+    }
+
+    /// The bytes every key in this page starts with. They are left out of the stored keys
+    pub fn prefix(&self) -> &[u8] {
+        &self.lower_fence()[..self.head().prefix_len as usize]
+    }
+
+    /// Where this page points at keys kept outside of it: the offset of the first overflow page of
+    /// every key in overflow pages, with the length of the key. Nothing gets dereferenced, so this
+    /// also works on a page whose pointers stand for something else, like ids in a data file
+    pub fn spilled_keys(&self, leaf: bool) -> Vec<(usize, usize)> {
+        let (nodes, _) = self.interpret();
+        let mut spilled = vec![];
+
+        for node in nodes {
+            let start = node.start as usize;
+            let key_start = match leaf {
+                true => {
+                    let value_len =
+                        u16::from_ne_bytes([self.body()[start], self.body()[start + 1]]);
+                    start + VALUE_LEN_SIZE + value_len as usize
+                }
+                false => start + PTR_SIZE,
+            };
+
+            if node.end as usize - key_start > Self::MAX_INLINE_KEY {
+                let len_start = key_start + Self::OVERFLOW_PREFIX_LEN;
+                let ptr_start = len_start + size_of::<usize>();
+                let len =
+                    usize::from_ne_bytes(self.body()[len_start..ptr_start].try_into().unwrap());
+
+                spilled.push((ptr_start, len));
+            }
         }
+
+        spilled
     }
 
-    pub fn value_at(&self, header: &FlexHead, index: usize) -> *mut () {
-        self.entry_at(header, index).1
+    /// The part of a branch entry holding its key, as `store_key` made it
+    pub fn heap_key_area(&self, node: &SlotNode) -> &[u8] {
+        self.get_heap_area(node).0
     }
 
-    pub fn entry_at(&self, header: &FlexHead, index: usize) -> (&str, *mut ()) {
-        let (nodes, _) = self.interpret(header);
-        self.get_heap_entry(header, &nodes[index])
+    /// The part of a leaf entry holding its key, as `store_key` made it
+    pub fn value_key_area(&self, node: &SlotNode) -> &[u8] {
+        self.get_value_area(node).0
     }
 
-    pub fn swap_ptr_at(&mut self, header: &FlexHead, index: usize, ptr: *mut ()) -> *mut () {
-        let (nodes, data) = self.interpret_mut(header);
-        let node = nodes[index];
+    pub fn cmp_heap_key(&self, node: &SlotNode, key: &[u8]) -> Ordering {
+        self.cmp_key_area(self.heap_key_area(node), key)
+    }
 
-        let data_offset = header.node_count as usize * size_of::<SlotNode>();
+    pub fn cmp_value_key(&self, node: &SlotNode, key: &[u8]) -> Ordering {
+        self.cmp_key_area(self.value_key_area(node), key)
+    }
 
-        let (ptr_slot, _) = data
-            [node.start as usize - data_offset..node.end as usize - data_offset]
-            .split_at_mut(PTR_SIZE);
+    /// Hands the overflow pages of a branch entry that no slot refers to any more back to `alloc`
+    pub fn release_heap_key<A: PageAllocator>(&self, node: &SlotNode, alloc: &A) {
+        if node.start != u16::MAX {
+            Self::release_key(self.heap_key_area(node), alloc);
+        }
+    }
+
+    /// Hands the overflow pages of a leaf entry that no slot refers to any more back to `alloc`
+    pub fn release_value_key<A: PageAllocator>(&self, node: &SlotNode, alloc: &A) {
+        if node.start != u16::MAX {
+            Self::release_key(self.value_key_area(node), alloc);
+        }
+    }
+
+    pub fn key_at(&self, index: usize) -> Cow<'_, [u8]> {
+        self.entry_at(index).0
+    }
+
+    pub fn value_at(&self, index: usize) -> *mut () {
+        let (nodes, _) = self.interpret();
+        self.get_heap_area(&nodes[index]).1
+    }
+
+    pub fn entry_at(&self, index: usize) -> (Cow<'_, [u8]>, *mut ()) {
+        let (nodes, _) = self.interpret();
+        self.get_heap_entry(&nodes[index])
+    }
+
+    pub fn swap_ptr_at(&mut self, index: usize, ptr: *mut ()) -> *mut () {
+        let node = self.interpret().0[index];
+
+        let (ptr_slot, _) =
+            self.body_mut()[node.start as usize..node.end as usize].split_at_mut(PTR_SIZE);
 
         let old_ptr = usize::from_ne_bytes(ptr_slot.try_into().ok().unwrap()) as *mut ();
 
@@ -119,103 +337,262 @@ impl Flex {
 
     pub fn swap_ptr_at_overflow<'a>(
         &mut self,
-        header: &FlexHead,
-        mut extra_slot: (&'a str, Node),
+        mut extra_slot: (&'a [u8], Node),
         index: usize,
         ptr: *mut (),
     ) -> *mut () {
-        if index == header.node_count as usize {
+        if index == self.head().node_count as usize {
             let old_ptr = extra_slot.1;
             extra_slot.1 = ptr;
             return old_ptr;
         }
 
-        self.swap_ptr_at(header, index, ptr)
+        self.swap_ptr_at(index, ptr)
     }
 
-    pub fn add_heap_entry(&mut self, header: &mut FlexHead, key: &str, value: *mut ()) -> SlotNode {
-        let (_, data) = self.interpret_mut(header);
+    /// Adds a branch entry. `key` is stored as it is, so it has to come from `store_key` or from
+    /// another branch
+    pub fn add_heap_entry(&mut self, key: &[u8], value: *mut ()) -> SlotNode {
+        debug_assert!(key.len() <= Self::OVERFLOW_STUB_LEN);
 
-        let slot_end = header.key_pos as usize;
+        let slot_end = self.head().key_pos as usize;
         let slot_len = key.len() + PTR_SIZE;
 
         let slot_start = slot_end - slot_len;
 
-        let data_offset = header.node_count as usize * size_of::<SlotNode>();
-
-        let data_slot = &mut data[slot_start - data_offset..slot_end - data_offset];
+        let data_slot = &mut self.body_mut()[slot_start..slot_end];
 
         let (ptr_slot, key_slot) = data_slot.split_at_mut(PTR_SIZE);
 
         ptr_slot.copy_from_slice(&(value as usize).to_ne_bytes());
+        key_slot.copy_from_slice(key);
 
-        debug_assert_eq!(key_slot.len(), key.len());
+        self.head_mut().key_pos = slot_start as u16;
 
-        key_slot.copy_from_slice(key.as_bytes());
+        SlotNode::new(slot_start as u16, slot_end as u16, self.key_hint(key))
+    }
 
-        header.key_pos = slot_start as u16;
+    /// Adds a leaf entry. `key` is stored as it is, so it has to come from `store_key` for the
+    /// prefix of this page, or from `moved_key`
+    pub fn add_value_entry(&mut self, key: &[u8], value: &[u8]) -> SlotNode {
+        debug_assert!(key.len() <= Self::OVERFLOW_STUB_LEN);
 
-        let mut u32_bytes = [0; 4];
+        let slot_end = self.head().key_pos as usize;
+        let slot_start = slot_end - VALUE_LEN_SIZE - value.len() - key.len();
 
-        let key_slice = if key.len() <= 4 { key } else { &key[0..4] };
+        let data_slot = &mut self.body_mut()[slot_start..slot_end];
 
-        for (i, byte) in key_slice.bytes().enumerate() {
-            u32_bytes[i] = byte;
-        }
+        let (len_slot, rest) = data_slot.split_at_mut(VALUE_LEN_SIZE);
+        let (value_slot, key_slot) = rest.split_at_mut(value.len());
 
-        SlotNode::new(
-            slot_start as u16,
-            slot_end as u16,
-            u32::from_be_bytes(u32_bytes),
-        )
+        len_slot.copy_from_slice(&(value.len() as u16).to_ne_bytes());
+        value_slot.copy_from_slice(value);
+        key_slot.copy_from_slice(key);
+
+        self.head_mut().key_pos = slot_start as u16;
+
+        SlotNode::new(slot_start as u16, slot_end as u16, self.key_hint(key))
     }
 
+    /// The key and child of a branch entry. A key in overflow pages is read back from them
+    pub fn get_heap_entry(&self, node: &SlotNode) -> (Cow<'_, [u8]>, Node) {
+        let (key, ptr) = self.get_heap_area(node);
+        (self.read_key(key), ptr)
+    }
+
+    /// The key area and child of a branch entry
     #[inline(always)]
-    pub fn get_heap_entry(&self, header: &FlexHead, node: &SlotNode) -> (&str, Node) {
-        let data_offset = header.node_count as usize * size_of::<SlotNode>();
-        let (_, data) = self.interpret(header);
+    pub fn get_heap_area(&self, node: &SlotNode) -> (&[u8], Node) {
+        let data_offset = self.head().node_count as usize * SLOT_SIZE;
+        let (_, data) = self.interpret();
 
         let data_slot = &data[node.start as usize - data_offset..node.end as usize - data_offset];
 
         let (ptr_slot, key_slot) = data_slot.split_at(PTR_SIZE);
 
         let ptr = usize::from_ne_bytes(ptr_slot.try_into().ok().unwrap()) as *mut ();
-        let key = unsafe { std::str::from_utf8_unchecked(key_slot) };
+        (key_slot, ptr)
+    }
 
-        (key, ptr)
+    /// The key and value bytes of a leaf entry. Only the part of the key behind the prefix of the
+    /// page is handed out, read back from overflow pages if that is where it is
+    pub fn get_value_entry(&self, node: &SlotNode) -> (Cow<'_, [u8]>, &[u8]) {
+        let (key, value) = self.get_value_area(node);
+        (self.read_key(key), value)
     }
 
-    pub fn get_upper_bound<'a>(&self, key: &str, header: &'a FlexHead) -> usize {
-        let (nodes, _) = self.interpret(header);
-        let mut slot_nr = 0;
+    /// The key area and value bytes of a leaf entry
+    #[inline(always)]
+    pub fn get_value_area(&self, node: &SlotNode) -> (&[u8], &[u8]) {
+        let data_offset = self.head().node_count as usize * SLOT_SIZE;
+        let (_, data) = self.interpret();
 
-        for node in nodes {
-            let (node_key, _) = self.get_heap_entry(&header, node);
-            if node_key >= key {
-                return slot_nr;
-            }
+        let data_slot = &data[node.start as usize - data_offset..node.end as usize - data_offset];
 
-            slot_nr += 1;
+        let (len_slot, rest) = data_slot.split_at(VALUE_LEN_SIZE);
+        let value_len = u16::from_ne_bytes(len_slot.try_into().ok().unwrap());
+        let (value, key) = rest.split_at(value_len as usize);
+
+        (key, value)
+    }
+
+    pub fn value_entry_at(&self, index: usize) -> (Cow<'_, [u8]>, &[u8]) {
+        let (nodes, _) = self.interpret();
+        self.get_value_entry(&nodes[index])
+    }
+
+    pub fn value_area_at(&self, index: usize) -> (&[u8], &[u8]) {
+        let (nodes, _) = self.interpret();
+        self.get_value_area(&nodes[index])
+    }
+
+    /// Overwrites the value of a leaf entry in place. The new value must have the same length
+    pub fn overwrite_value_at(&mut self, index: usize, value: &[u8]) {
+        let data_offset = self.head().node_count as usize * SLOT_SIZE;
+        let (nodes, data) = self.interpret_mut();
+        let node = nodes[index];
+
+        let value_start = node.start as usize - data_offset + VALUE_LEN_SIZE;
+
+        debug_assert_eq!(
+            value.len(),
+            u16::from_ne_bytes([data[value_start - 2], data[value_start - 1]]) as usize
+        );
+
+        data[value_start..value_start + value.len()].copy_from_slice(value);
+    }
+
+    /// Like `get_value_area`, but for the slot standing in for `extra_slot` while a split is
+    /// worked out
+    pub fn get_overflow_value_area<'a>(
+        &'a self,
+        node: &SlotNode,
+        extra_slot: (&'a [u8], &'a [u8]),
+    ) -> (&'a [u8], &'a [u8]) {
+        if node.start == u16::MAX {
+            return extra_slot;
         }
+        self.get_value_area(node)
+    }
+
+    pub fn get_upper_bound(&self, key: &[u8]) -> usize {
+        self.search(key, false, |node| self.cmp_heap_key(node, key))
+    }
+
+    /// The first slot whose key is not smaller than `key`, or with `after` set, the first one that
+    /// is larger. `cmp_key` compares the key of a slot against `key`
+    pub fn search(
+        &self,
+        key: &[u8],
+        after: bool,
+        cmp_key: impl Fn(&SlotNode) -> Ordering,
+    ) -> usize {
+        #[cfg(feature = "simd-hints")]
+        return self.search_hints(key, after, cmp_key);
+
+        #[cfg(not(feature = "simd-hints"))]
+        return self.search_slots(key, after, cmp_key);
+    }
+
+    /// Binary search over the slot array. `first_bytes` settles most comparisons, `cmp_key` only
+    /// has to look at the heap when the hints tie
+    pub fn search_slots(
+        &self,
+        key: &[u8],
+        after: bool,
+        cmp_key: impl Fn(&SlotNode) -> Ordering,
+    ) -> usize {
+        let (nodes, _) = self.interpret();
+        let hint = first_bytes(key);
+
+        nodes.partition_point(|node| {
+            let ord = node.first_bytes.cmp(&hint).then_with(|| cmp_key(node));
+
+            if after {
+                ord.is_le()
+            } else {
+                ord.is_lt()
+            }
+        })
+    }
+
+    /// Scans the hints a vector at a time for the first one that is not below the hint of `key`.
+    /// The slots from there on that tie with it are binary searched with `cmp_key`
+    #[cfg(feature = "simd-hints")]
+    pub fn search_hints(
+        &self,
+        key: &[u8],
+        after: bool,
+        cmp_key: impl Fn(&SlotNode) -> Ordering,
+    ) -> usize {
+        use std::simd::{cmp::SimdPartialOrd, Simd};
+
+        const LANES: usize = 8;
+
+        let hints = self.hints();
+        let hint = first_bytes(key);
+        let needle = Simd::<u32, LANES>::splat(hint);
+
+        let (chunks, rest) = hints.as_chunks::<LANES>();
+        let mut below = 0;
+
+        let first_chunk = chunks.iter().position(|chunk| {
+            let mask = Simd::from_array(*chunk).simd_lt(needle);
+            // the hints are sorted, so the lanes that are below are always the leading ones
+            below = mask.to_bitmask().count_ones() as usize;
+            !mask.all()
+        });
+
+        let below = match first_chunk {
+            Some(chunk) => chunk * LANES + below,
+            None => chunks.len() * LANES + rest.iter().take_while(|&&other| other < hint).count(),
+        };
+
+        let (nodes, _) = self.interpret();
 
-        slot_nr
+        below
+            + nodes[below..].partition_point(|node| {
+                node.first_bytes == hint
+                    && if after {
+                        cmp_key(node).is_le()
+                    } else {
+                        cmp_key(node).is_lt()
+                    }
+            })
     }
 
-    pub fn get_overflow_heap_entry<'a>(
+    /// Like `get_heap_area`, but for the slot standing in for `extra_slot` while a split is
+    /// worked out
+    pub fn get_overflow_heap_area<'a>(
         &'a self,
-        header: &FlexHead,
         node: &SlotNode,
-        extra_slot: (&'a str, Node),
-    ) -> (&'a str, Node) {
+        extra_slot: (&'a [u8], Node),
+    ) -> (&'a [u8], Node) {
         if node.start == u16::MAX {
             return extra_slot;
         }
-        self.get_heap_entry(header, node)
+        self.get_heap_area(node)
     }
 
-    pub fn insert_stack(&mut self, header: &mut FlexHead, index: usize, entry: SlotNode) {
-        header.node_count += 1;
-        let (nodes, _) = self.interpret_mut(header);
+    pub fn insert_stack(&mut self, index: usize, entry: SlotNode) {
+        #[cfg(feature = "simd-hints")]
+        {
+            // the hints move behind the grown slot array first, so it does not overwrite them
+            let count = self.head().node_count as usize;
+            let start = count * size_of::<SlotNode>();
+
+            self.body_mut().copy_within(
+                start..start + count * HINT_SIZE,
+                start + size_of::<SlotNode>(),
+            );
+
+            let hints = self.hints_mut(count + 1);
+            hints.copy_within(index..count, index + 1);
+            hints[index] = entry.first_bytes;
+        }
+
+        self.head_mut().node_count += 1;
+        let (nodes, _) = self.interpret_mut();
         let mut cur_node = entry;
 
         for node in &mut nodes[index..] {
@@ -226,20 +603,458 @@ impl Flex {
         // drop cur_entry here
     }
 
-    pub fn insert_stack_overflow(
-        &mut self,
-        header: &FlexHead,
-        index: usize,
-        entry: SlotNode,
-    ) -> SlotNode {
-        // do not increase size, return last node instead
-        let (nodes, _) = self.interpret_mut(header);
-        let mut cur_node = entry;
+    pub fn remove_stack(&mut self, index: usize) -> SlotNode {
+        let (nodes, _) = self.interpret_mut();
+        let removed = nodes[index];
 
-        for node in &mut nodes[index..] {
-            std::mem::swap(node, &mut cur_node);
+        nodes.copy_within(index + 1.., index);
+
+        #[cfg(feature = "simd-hints")]
+        {
+            let count = self.head().node_count as usize;
+            let start = count * size_of::<SlotNode>();
+
+            self.hints_mut(count).copy_within(index + 1.., index);
+            self.body_mut().copy_within(
+                start..start + (count - 1) * HINT_SIZE,
+                start - size_of::<SlotNode>(),
+            );
+        }
+
+        // the heap entry stays where it is. Its bytes are lost until the page gets rebuilt
+        self.head_mut().node_count -= 1;
+
+        removed
+    }
+
+    /// Bytes taken up by slots and heap entries that are still referenced. Unlike
+    /// `DATA_LEN - key_pos` this does not count the holes left behind by removals
+    pub fn live_bytes(&self) -> usize {
+        let (nodes, _) = self.interpret();
+
+        nodes
+            .iter()
+            .map(|node| (node.end - node.start) as usize + SLOT_SIZE)
+            .sum()
+    }
+
+    /// Heap bytes left behind by removed or replaced entries, which only `compact` gets back
+    pub fn fragmented_bytes(&self) -> usize {
+        let (nodes, _) = self.interpret();
+        let live: usize = nodes
+            .iter()
+            .map(|node| (node.end - node.start) as usize)
+            .sum();
+
+        (self.head().fences - self.head().key_pos) as usize - live
+    }
+
+    /// Rewrites the live heap entries back to back below the fences and points the slots at their
+    /// new places, so the holes between them become free space again
+    pub fn compact(&mut self) {
+        let heap_start = self.head().key_pos as usize;
+        let fences = self.head().fences as usize;
+
+        // entries may move onto bytes of others that have not moved yet, so copy from the old heap
+        let heap = self.body()[heap_start..fences].to_vec();
+
+        let (nodes, _) = self.interpret_mut();
+        let mut moves = Vec::with_capacity(nodes.len());
+        let mut key_pos = fences;
+
+        for node in nodes.iter_mut() {
+            let len = (node.end - node.start) as usize;
+            key_pos -= len;
+
+            moves.push((node.start as usize - heap_start, key_pos, len));
+
+            node.start = key_pos as u16;
+            node.end = (key_pos + len) as u16;
+        }
+
+        for (old_start, new_start, len) in moves {
+            self.body_mut()[new_start..new_start + len]
+                .copy_from_slice(&heap[old_start..old_start + len]);
+        }
+
+        self.head_mut().key_pos = key_pos as u16;
+    }
+}
+
+/// The first four bytes of `key`, zero padded, as a big endian number. Comparing these orders keys
+/// the same way as comparing the keys themselves, as long as they differ in the first four bytes
+pub fn first_bytes(key: &[u8]) -> u32 {
+    let mut u32_bytes = [0; 4];
+    let key_slice = if key.len() <= 4 { key } else { &key[0..4] };
+
+    for (i, byte) in key_slice.iter().enumerate() {
+        u32_bytes[i] = *byte;
+    }
+
+    u32::from_be_bytes(u32_bytes)
+}
+
+/// Reads a pointer back from the value bytes it was stored as. None if there are not as many bytes
+/// as a pointer has, like for most values stored inline
+pub fn ptr_from_bytes(bytes: &[u8]) -> Option<Node> {
+    Some(usize::from_ne_bytes(bytes.try_into().ok()?) as Node)
+}
+
+pub fn ptr_to_bytes(ptr: Node) -> [u8; size_of::<usize>()] {
+    (ptr as usize).to_ne_bytes()
+}
+
+/// The fences a page records for the keys from `lower` up to `upper`. Keys longer than
+/// MAX_INLINE_FENCE are cut short: the lower one to its first bytes, the upper one to the smallest
+/// key of at most that length above it, or none if there is no such key. The shorter fences are
+/// looser, but still bound every key the given ones do
+pub fn truncate_fences<'a, 'b>(
+    lower: &'a [u8],
+    upper: Option<&'b [u8]>,
+) -> (&'a [u8], Option<Cow<'b, [u8]>>) {
+    let lower = &lower[..lower.len().min(MAX_INLINE_FENCE)];
+
+    let upper = match upper {
+        Some(upper) if upper.len() > MAX_INLINE_FENCE => {
+            // counting up the last byte that is not 0xFF gives the next key after the first bytes
+            let mut short = upper[..MAX_INLINE_FENCE].to_vec();
+
+            while short.last() == Some(&u8::MAX) {
+                short.pop();
+            }
+
+            match short.last_mut() {
+                Some(last) => {
+                    *last += 1;
+                    Some(Cow::Owned(short))
+                }
+                None => None,
+            }
+        }
+        upper => upper.map(Cow::Borrowed),
+    };
+
+    (lower, upper)
+}
+
+/// Page bytes taken up by fences with these keys
+pub fn fences_len(lower: &[u8], upper: Option<&[u8]>) -> usize {
+    let (lower, upper) = truncate_fences(lower, upper);
+    fence_record_len(Some(lower)) + fence_record_len(upper.as_deref())
+}
+
+/// Length of the prefix shared by every key between the two fences. A page that is unbounded above
+/// has none, as any key larger than the lower fence may end up in it
+pub fn fence_prefix_len(lower: &[u8], upper: Option<&[u8]>) -> usize {
+    let (lower, upper) = truncate_fences(lower, upper);
+
+    match upper {
+        Some(upper) => lower
+            .iter()
+            .zip(upper.iter())
+            .take_while(|(left, right)| left == right)
+            .count(),
+        None => 0,
+    }
+}
+
+/// The split points at most `window` away from `midpoint` that lie in `first..=last`, closest
+/// first. Ties in separator length are decided by the order these come in
+pub fn split_candidates(
+    midpoint: usize,
+    window: usize,
+    first: usize,
+    last: usize,
+) -> impl Iterator<Item = usize> {
+    iter::once(midpoint)
+        .chain(
+            (1..=window)
+                .flat_map(move |distance| {
+                    [
+                        midpoint.checked_sub(distance),
+                        midpoint.checked_add(distance),
+                    ]
+                })
+                .flatten(),
+        )
+        .filter(move |index| (first..=last).contains(index))
+}
+
+/// Whether `key` may be stored in a page with these fences
+pub fn within_fences(lower: &[u8], upper: Option<&[u8]>, key: &[u8]) -> bool {
+    lower <= key && upper.is_none_or(|upper| key < upper)
+}
+
+fn fence_record_len(fence: Option<&[u8]>) -> usize {
+    match fence {
+        Some(key) => FENCE_LEN_SIZE + key.len(),
+        None => FENCE_LEN_SIZE,
+    }
+}
+
+fn write_fence(area: &mut [u8], fence: Option<&[u8]>) {
+    let (len, rest) = area.split_at_mut(FENCE_LEN_SIZE);
+
+    let Some(key) = fence else {
+        len.copy_from_slice(&NO_FENCE.to_ne_bytes());
+        return;
+    };
+
+    len.copy_from_slice(&(key.len() as u16).to_ne_bytes());
+    rest.copy_from_slice(key);
+}
+
+// the fence at the start of `area` and how many bytes its record takes up
+fn read_fence(area: &[u8]) -> (Option<&[u8]>, usize) {
+    let len = u16::from_ne_bytes(area[..FENCE_LEN_SIZE].try_into().ok().unwrap());
+
+    if len == NO_FENCE {
+        return (None, FENCE_LEN_SIZE);
+    }
+
+    let len = len as usize;
+
+    (
+        Some(&area[FENCE_LEN_SIZE..FENCE_LEN_SIZE + len]),
+        FENCE_LEN_SIZE + len,
+    )
+}
+
+// a page of the chain holding a key that is too long for its node: the next page of the chain, or 0
+// at its end, then as many bytes of the key as fit
+#[repr(C, align(8))]
+struct OverflowPage<const P: usize> {
+    raw: [u8; P],
+}
+
+const CHAIN_LINK_LEN: usize = size_of::<usize>();
+
+impl<const P: usize> Flex<P> {
+    // key bytes in every page of an overflow chain
+    const CHUNK_LEN: usize = P - CHAIN_LINK_LEN;
+
+    /// How many bytes a key of `key_len` bytes takes up in a page that leaves out the first
+    /// `prefix_len` of them. A key area as `store_key` made it, with `prefix_len` 0, stays as long
+    /// as it is
+    pub fn stored_key_len(key_len: usize, prefix_len: usize) -> usize {
+        if key_len > Self::MAX_INLINE_KEY {
+            Self::OVERFLOW_STUB_LEN
+        } else {
+            key_len - prefix_len
+        }
+    }
+
+    /// Overflow pages a key of `key_len` bytes is kept in, 0 if it stays in its page
+    pub fn overflow_pages(key_len: usize) -> usize {
+        if key_len > Self::MAX_INLINE_KEY {
+            key_len.div_ceil(Self::CHUNK_LEN)
+        } else {
+            0
+        }
+    }
+
+    /// The length of the whole key stored as `area` in a page with a prefix of `prefix_len` bytes
+    pub fn key_len(prefix_len: usize, area: &[u8]) -> usize {
+        match Self::overflow_of(area) {
+            Some((_, len)) => len,
+            None => prefix_len + area.len(),
         }
+    }
+
+    /// What a page with `prefix` stores of `key`, which is everything behind that prefix: the key
+    /// itself, or a stub pointing at a new chain of overflow pages from `alloc` holding the whole
+    /// key. Fails if `alloc` runs out of pages for the chain.
+    pub fn store_key<'a, A: PageAllocator>(
+        prefix: &[u8],
+        key: &'a [u8],
+        alloc: &A,
+    ) -> Result<Cow<'a, [u8]>, BTreeError> {
+        let len = prefix.len() + key.len();
+
+        if len <= Self::MAX_INLINE_KEY {
+            return Ok(Cow::Borrowed(key));
+        }
+
+        let whole = [prefix, key].concat();
+        let first = Self::write_overflow(&whole, alloc)?;
+
+        let mut stub = whole[..Self::OVERFLOW_PREFIX_LEN].to_vec();
+        stub.extend_from_slice(&len.to_ne_bytes());
+        stub.extend_from_slice(&first.to_ne_bytes());
+
+        Ok(Cow::Owned(stub))
+    }
+
+    /// Hands the overflow pages of a key area back to `alloc`. A key kept in its page has none
+    pub fn release_key<A: PageAllocator>(area: &[u8], alloc: &A) {
+        if let Some((first, _)) = Self::overflow_of(area) {
+            Self::release_overflow(first, alloc);
+        }
+    }
+
+    /// `area`, stored in a page with `prefix`, as this page stores it. Only keys kept in the page
+    /// change, the stub of one in overflow pages is the same everywhere
+    pub fn moved_key<'a>(&self, prefix: &[u8], area: &'a [u8]) -> Cow<'a, [u8]> {
+        let prefix_len = self.head().prefix_len as usize;
+
+        if area.len() > Self::MAX_INLINE_KEY {
+            return Cow::Borrowed(area);
+        }
+
+        match prefix_len.checked_sub(prefix.len()) {
+            Some(strip) => Cow::Borrowed(&area[strip..]),
+            None => Cow::Owned([&prefix[prefix_len..], area].concat()),
+        }
+    }
+
+    /// The whole key stored as `area` in a page with `prefix`
+    pub fn whole_key<'a>(prefix: &[u8], area: &'a [u8]) -> Cow<'a, [u8]> {
+        match Self::overflow_of(area) {
+            Some((first, len)) => Cow::Owned(Self::read_overflow(first, len)),
+            None if prefix.is_empty() => Cow::Borrowed(area),
+            None => Cow::Owned([prefix, area].concat()),
+        }
+    }
+
+    /// The key stored as `area` in this page, without the prefix of the page
+    pub fn read_key<'a>(&self, area: &'a [u8]) -> Cow<'a, [u8]> {
+        match Self::overflow_of(area) {
+            Some((first, len)) => {
+                let mut key = Self::read_overflow(first, len);
+                key.drain(..self.head().prefix_len as usize);
+                Cow::Owned(key)
+            }
+            None => Cow::Borrowed(area),
+        }
+    }
+
+    // `first_bytes` of the key stored as `area` in this page. The stub of a key in overflow pages
+    // usually holds enough of it
+    fn key_hint(&self, area: &[u8]) -> u32 {
+        let prefix_len = self.head().prefix_len as usize;
+
+        if area.len() <= Self::MAX_INLINE_KEY {
+            first_bytes(area)
+        } else if prefix_len + 4 <= Self::OVERFLOW_PREFIX_LEN {
+            first_bytes(&area[prefix_len..Self::OVERFLOW_PREFIX_LEN])
+        } else {
+            first_bytes(&self.read_key(area))
+        }
+    }
+
+    // compares the key stored as `area` in this page against `key`, which has the prefix of the
+    // page taken off
+    fn cmp_key_area(&self, area: &[u8], key: &[u8]) -> Ordering {
+        let Some((first, len)) = Self::overflow_of(area) else {
+            return area.cmp(key);
+        };
+
+        let prefix_len = self.head().prefix_len as usize;
+
+        if prefix_len < Self::OVERFLOW_PREFIX_LEN {
+            let head = &area[prefix_len..Self::OVERFLOW_PREFIX_LEN];
+            let key_head = &key[..usize::min(key.len(), head.len())];
+
+            if let ordering @ (Ordering::Less | Ordering::Greater) = head.cmp(key_head) {
+                return ordering;
+            }
+        }
+
+        Self::overflow_chunks(first, len)
+            .flatten()
+            .skip(prefix_len)
+            .cmp(key.iter())
+    }
+
+    // the first overflow page and the key length in a stub, None if `area` is a key kept in its
+    // page
+    fn overflow_of(area: &[u8]) -> Option<(usize, usize)> {
+        if area.len() <= Self::MAX_INLINE_KEY {
+            return None;
+        }
+
+        let (len, first) = area[Self::OVERFLOW_PREFIX_LEN..].split_at(size_of::<usize>());
+
+        let len = usize::from_ne_bytes(len.try_into().ok().unwrap());
+        let first = usize::from_ne_bytes(first.try_into().ok().unwrap());
+
+        Some((first, len))
+    }
+
+    /// Puts `key` into a chain of overflow pages from `alloc` and returns the address of the first
+    pub fn write_overflow<A: PageAllocator>(key: &[u8], alloc: &A) -> Result<usize, BTreeError> {
+        // written from the back, so every page can link to the one after it right away
+        let mut next = 0;
+
+        for chunk in key.chunks(Self::CHUNK_LEN).rev() {
+            let Some(page) = alloc.allocate(Self::overflow_layout()) else {
+                Self::release_overflow(next, alloc);
+                return Err(BTreeError::OutOfPages);
+            };
+
+            let raw = unsafe { slice::from_raw_parts_mut(page.as_ptr(), P) };
+            raw.fill(0);
+            Self::set_next_overflow(raw, next);
+            raw[CHAIN_LINK_LEN..CHAIN_LINK_LEN + chunk.len()].copy_from_slice(chunk);
+
+            next = page.as_ptr() as usize;
+        }
+
+        Ok(next)
+    }
+
+    /// The `len` bytes of the key in the overflow chain starting at `first`
+    pub fn read_overflow(first: usize, len: usize) -> Vec<u8> {
+        let mut key = Vec::with_capacity(len);
+
+        for chunk in Self::overflow_chunks(first, len) {
+            key.extend_from_slice(chunk);
+        }
+
+        key
+    }
+
+    // the pieces of the key in the overflow chain starting at `first`, one per page. The chain
+    // lives as long as the entry pointing at it, which can't outlive the page we borrowed
+    fn overflow_chunks<'a>(first: usize, len: usize) -> impl Iterator<Item = &'a [u8]> {
+        let mut page = first;
+        let mut left = len;
+
+        iter::from_fn(move || {
+            if left == 0 {
+                return None;
+            }
+
+            let raw = unsafe { &(*(page as *const OverflowPage<P>)).raw };
+            let chunk = &raw[CHAIN_LINK_LEN..CHAIN_LINK_LEN + left.min(Self::CHUNK_LEN)];
+
+            left -= chunk.len();
+            page = Self::next_overflow(raw);
+
+            Some(chunk)
+        })
+    }
+
+    fn release_overflow<A: PageAllocator>(first: usize, alloc: &A) {
+        let mut page = first;
+
+        while let Some(raw) = NonNull::new(page as *mut u8) {
+            page = Self::next_overflow(unsafe { &(*(page as *const OverflowPage<P>)).raw });
+            unsafe { alloc.deallocate(raw, Self::overflow_layout()) };
+        }
+    }
+
+    /// Overflow pages are plain memory from `PageAllocator::allocate` with this layout
+    pub fn overflow_layout() -> Layout {
+        Layout::new::<OverflowPage<P>>()
+    }
+
+    /// The page after the overflow page `page` in its chain, or 0 at the end of it
+    pub fn next_overflow(page: &[u8]) -> usize {
+        usize::from_ne_bytes(page[..CHAIN_LINK_LEN].try_into().unwrap())
+    }
 
-        cur_node
+    pub fn set_next_overflow(page: &mut [u8], next: usize) {
+        page[..CHAIN_LINK_LEN].copy_from_slice(&next.to_ne_bytes());
     }
 }
@@ -0,0 +1,386 @@
+use std::{borrow::Cow, fmt::Debug, ptr::NonNull};
+
+use crate::{
+    btree::{BTreeError, Node},
+    page_alloc::{Heap, PageAllocator},
+    slotted_leaf::SlottedLeaf,
+    PAGE_SIZE,
+};
+
+/// Walks the leaf chain between two positions, from the front towards the back or the other way
+/// around. Keys are borrowed from their page, unless it keeps a prefix apart from them. Then they
+/// are put back together and handed out owned. A range whose leaves could not be found or read
+/// ends early, [`Range::error`] tells why. Created by
+/// [`BTree::range`](crate::btree::BTree::range).
+pub struct Range<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    // missing if the tree could not be descended to either end
+    walk: Option<Walk<'a, T, P, A>>,
+    // why the descent failed
+    error: Option<BTreeError>,
+}
+
+// the leaf chain between the ends of a range. The leaves at both ends are pinned while it is on them
+struct Walk<'a, T: Debug, const P: usize, A: PageAllocator> {
+    // the next entry to yield from the front
+    front: (&'a SlottedLeaf<T, P>, usize),
+    // one past the next entry to yield from the back
+    back: (&'a SlottedLeaf<T, P>, usize),
+    done: bool,
+    // where the leaves along the chain are kept
+    alloc: &'a A,
+    // a leaf along the chain could not be read, which ended the range early
+    error: Option<BTreeError>,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Range<'a, T, P, A> {
+    pub(crate) fn new(
+        front: (&'a SlottedLeaf<T, P>, usize),
+        back: (&'a SlottedLeaf<T, P>, usize),
+        alloc: &'a A,
+    ) -> Self {
+        Self {
+            walk: Some(Walk::new(front, back, alloc)),
+            error: None,
+        }
+    }
+
+    pub(crate) fn empty(leaf: &'a SlottedLeaf<T, P>, alloc: &'a A) -> Self {
+        let mut walk = Walk::new((leaf, 0), (leaf, 0), alloc);
+        walk.done = true;
+
+        Self {
+            walk: Some(walk),
+            error: None,
+        }
+    }
+
+    pub(crate) fn failed(error: BTreeError) -> Self {
+        Self {
+            walk: None,
+            error: Some(error),
+        }
+    }
+
+    /// Why the range ended before it got to its end, if it did. Either the tree could not be
+    /// descended to one of its ends or a leaf along the way could not be read
+    pub fn error(&self) -> Option<&BTreeError> {
+        self.error
+            .as_ref()
+            .or_else(|| self.walk.as_ref()?.error.as_ref())
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<BTreeError> {
+        self.error
+            .take()
+            .or_else(|| self.walk.as_mut()?.error.take())
+    }
+
+    /// Yields the value bytes stored by [`BTree::insert_value`](crate::btree::BTree::insert_value)
+    /// instead of pointers.
+    pub fn inline_values(self) -> InlineValues<'a, T, P, A> {
+        InlineValues { inner: self }
+    }
+
+    fn next_position(&mut self) -> Option<(&'a SlottedLeaf<T, P>, usize)> {
+        self.walk.as_mut()?.next_position()
+    }
+
+    fn next_back_position(&mut self) -> Option<(&'a SlottedLeaf<T, P>, usize)> {
+        self.walk.as_mut()?.next_back_position()
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Walk<'a, T, P, A> {
+    fn new(
+        front: (&'a SlottedLeaf<T, P>, usize),
+        back: (&'a SlottedLeaf<T, P>, usize),
+        alloc: &'a A,
+    ) -> Self {
+        alloc.pin(page(front.0));
+        alloc.pin(page(back.0));
+
+        Self {
+            front,
+            back,
+            done: false,
+            alloc,
+            error: None,
+        }
+    }
+
+    // the leaf `node` refers to. If it can't be read, the range ends here
+    fn leaf(&mut self, node: NonNull<()>) -> Option<&'a SlottedLeaf<T, P>> {
+        match self.alloc.try_page(node.as_ptr()) {
+            Ok(leaf) => Some(unsafe { &*(leaf as *const SlottedLeaf<T, P>) }),
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
+
+    // moves an end of the range from leaf `from` onto leaf `to`, which stays pinned instead
+    fn repin(&self, from: &SlottedLeaf<T, P>, to: &SlottedLeaf<T, P>) {
+        self.alloc.pin(page(to));
+        self.alloc.unpin(page(from));
+    }
+
+    // moves the front onto the next entry that actually exists. leaves can be empty after
+    // removals, so we may have to skip more than one
+    fn settle_front(&mut self) -> bool {
+        while self.front.1 >= self.front.0.size() {
+            let Some(next) = self.front.0.next_leaf() else {
+                return false;
+            };
+
+            let Some(leaf) = self.leaf(next) else {
+                return false;
+            };
+
+            self.repin(self.front.0, leaf);
+            self.front = (leaf, 0);
+        }
+
+        true
+    }
+
+    // both ends point in between two entries. They have met once there is nothing between them
+    fn exhausted(&mut self) -> bool {
+        if !self.settle_front() {
+            return true;
+        }
+
+        let (mut leaf, mut index) = self.back;
+
+        while index >= leaf.size() {
+            let Some(next) = leaf.next_leaf() else {
+                return false;
+            };
+
+            let Some(next) = self.leaf(next) else {
+                return true;
+            };
+
+            (leaf, index) = (next, 0);
+        }
+
+        std::ptr::eq(leaf, self.front.0) && index == self.front.1
+    }
+
+    fn next_position(&mut self) -> Option<(&'a SlottedLeaf<T, P>, usize)> {
+        if self.done || self.exhausted() {
+            self.done = true;
+            return None;
+        }
+
+        let (leaf, index) = self.front;
+        self.front.1 += 1;
+
+        Some((leaf, index))
+    }
+
+    fn next_back_position(&mut self) -> Option<(&'a SlottedLeaf<T, P>, usize)> {
+        if self.done || self.exhausted() {
+            self.done = true;
+            return None;
+        }
+
+        while self.back.1 == 0 {
+            let prev = self
+                .back
+                .0
+                .prev_leaf()
+                .expect("Leaf chain ended before the front");
+            let Some(leaf) = self.leaf(prev) else {
+                self.done = true;
+                return None;
+            };
+
+            self.repin(self.back.0, leaf);
+            self.back = (leaf, leaf.size());
+        }
+
+        self.back.1 -= 1;
+        Some(self.back)
+    }
+}
+
+impl<T: Debug, const P: usize, A: PageAllocator> Drop for Walk<'_, T, P, A> {
+    fn drop(&mut self) {
+        self.alloc.unpin(page(self.front.0));
+        self.alloc.unpin(page(self.back.0));
+    }
+}
+
+fn page<T: Debug, const P: usize>(leaf: &SlottedLeaf<T, P>) -> Node {
+    leaf as *const SlottedLeaf<T, P> as Node
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Iterator for Range<'a, T, P, A> {
+    type Item = (Cow<'a, [u8]>, *mut T);
+
+    // entries holding a value stored inline have no pointer to hand out. They are skipped, just
+    // like `get` doesn't find them
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (leaf, index) = self.next_position()?;
+
+            if let Some(value) = leaf.value_at(index) {
+                return Some((leaf.key_at(index), value));
+            }
+        }
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> DoubleEndedIterator for Range<'a, T, P, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let (leaf, index) = self.next_back_position()?;
+
+            if let Some(value) = leaf.value_at(index) {
+                return Some((leaf.key_at(index), value));
+            }
+        }
+    }
+}
+
+/// A [`Range`] over a tree that keeps its values inline. Created by [`Range::inline_values`].
+pub struct InlineValues<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    inner: Range<'a, T, P, A>,
+}
+
+impl<T: Debug, const P: usize, A: PageAllocator> InlineValues<'_, T, P, A> {
+    pub(crate) fn take_error(&mut self) -> Option<BTreeError> {
+        self.inner.take_error()
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Iterator for InlineValues<'a, T, P, A> {
+    type Item = (Cow<'a, [u8]>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (leaf, index) = self.inner.next_position()?;
+        Some((leaf.key_at(index), leaf.value_bytes_at(index)))
+    }
+}
+
+impl<T: Debug, const P: usize, A: PageAllocator> DoubleEndedIterator for InlineValues<'_, T, P, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (leaf, index) = self.inner.next_back_position()?;
+        Some((leaf.key_at(index), leaf.value_bytes_at(index)))
+    }
+}
+
+/// All entries of a tree in ascending key order. Created by
+/// [`BTree::iter`](crate::btree::BTree::iter).
+pub struct Iter<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    inner: Range<'a, T, P, A>,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Iter<'a, T, P, A> {
+    pub(crate) fn new(inner: Range<'a, T, P, A>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Iterator for Iter<'a, T, P, A> {
+    type Item = (Cow<'a, [u8]>, *mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl<T: Debug, const P: usize, A: PageAllocator> DoubleEndedIterator for Iter<'_, T, P, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// Created by [`BTree::keys`](crate::btree::BTree::keys).
+pub struct Keys<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    inner: Iter<'a, T, P, A>,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Keys<'a, T, P, A> {
+    pub(crate) fn new(inner: Iter<'a, T, P, A>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Iterator for Keys<'a, T, P, A> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, _)| key)
+    }
+}
+
+impl<T: Debug, const P: usize, A: PageAllocator> DoubleEndedIterator for Keys<'_, T, P, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(key, _)| key)
+    }
+}
+
+/// Created by [`BTree::values`](crate::btree::BTree::values).
+pub struct Values<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    inner: Iter<'a, T, P, A>,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Values<'a, T, P, A> {
+    pub(crate) fn new(inner: Iter<'a, T, P, A>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Iterator for Values<'a, T, P, A> {
+    type Item = *mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, value)| value)
+    }
+}
+
+impl<T: Debug, const P: usize, A: PageAllocator> DoubleEndedIterator for Values<'_, T, P, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(_, value)| value)
+    }
+}
+
+/// Entries whose keys start with a prefix, in ascending order. Created by
+/// [`BTree::prefix`](crate::btree::BTree::prefix).
+pub struct Prefix<'a, T: Debug, const P: usize = PAGE_SIZE, A: PageAllocator = Heap> {
+    inner: Range<'a, T, P, A>,
+    prefix: Vec<u8>,
+    done: bool,
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Prefix<'a, T, P, A> {
+    pub(crate) fn new(inner: Range<'a, T, P, A>, prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            prefix,
+            done: false,
+        }
+    }
+}
+
+impl<'a, T: Debug, const P: usize, A: PageAllocator> Iterator for Prefix<'a, T, P, A> {
+    type Item = (Cow<'a, [u8]>, *mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let (key, value) = self.inner.next()?;
+
+        // keys sharing the prefix are contiguous, so the first one that doesn't ends the scan
+        if !key.starts_with(&self.prefix) {
+            self.done = true;
+            return None;
+        }
+
+        Some((key, value))
+    }
+}
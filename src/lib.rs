@@ -1,13 +1,22 @@
 #![feature(test)]
+#![cfg_attr(feature = "simd-hints", feature(portable_simd))]
 pub mod bees;
 pub mod btree;
+pub mod cursor;
+pub mod encoding;
+pub mod entry;
 pub mod ffi;
 pub mod flex;
+pub mod iter;
+pub mod map;
+pub mod page_alloc;
+pub mod persist;
 pub mod slotted_branch;
 pub mod slotted_leaf;
 pub mod visualize;
 
-pub static PAGE_SIZE: usize = 4096;
+// page size used unless a tree is given another one
+pub const PAGE_SIZE: usize = 4096;
 
 pub static PTR_SIZE: usize = std::mem::size_of::<*const u8>();
 
@@ -17,13 +26,34 @@ mod btree_test {
     use crate::{bees::BEES, slotted_branch::SlottedBranch, slotted_leaf::SlottedLeaf};
     // only used for deduplication. I am aware of the irony
     use std::collections::HashSet;
+    use std::ops::{Bound, RangeBounds, RangeFull};
 
-    use super::btree::BTree;
+    use super::btree::{BTree, BTreeError, BulkLoadError, InsertResult};
+    use super::ffi::{
+        ffi_btree_drop, ffi_btree_get, ffi_btree_get_with_status, ffi_btree_insert,
+        ffi_btree_insert_if_absent, ffi_btree_insert_with_result, ffi_btree_new, FfiGetResult,
+        FfiInsertResult,
+    };
+    use super::map::BTreeMap;
+    use super::page_alloc::{Arena, PageAllocator, PagePool};
+    use super::persist::FileTree;
+
+    const MAX_VALUE_LEN: usize = SlottedLeaf::<()>::MAX_VALUE_LEN;
+
+    fn check_page_size<const P: usize>() {
+        assert_eq!(size_of::<SlottedLeaf<(), P>>(), P);
+        assert_eq!(size_of::<SlottedBranch<(), P>>(), P);
+    }
 
     #[test]
     fn sizes() {
-        assert_eq!(size_of::<SlottedLeaf<()>>(), 4096);
-        assert_eq!(size_of::<SlottedBranch<()>>(), 4096);
+        assert_eq!(size_of::<SlottedLeaf<()>>(), super::PAGE_SIZE);
+        assert_eq!(size_of::<SlottedBranch<()>>(), super::PAGE_SIZE);
+
+        check_page_size::<1024>();
+        check_page_size::<4096>();
+        check_page_size::<16384>();
+        check_page_size::<65536>();
     }
 
     #[test]
@@ -32,9 +62,500 @@ mod btree_test {
 
         let location = 6942 as *mut ();
 
-        tree.insert("hello", location);
+        tree.insert("hello", location).unwrap();
+
+        assert_eq!(tree.get("hello").unwrap(), Some(location));
+    }
+
+    #[test]
+    fn replace() {
+        let mut tree = BTree::new();
+
+        for i in 0..10_000 {
+            let key_value = format!("{i:016}");
+            assert_eq!(
+                tree.insert(&key_value, i as *mut ()).unwrap(),
+                InsertResult::Inserted
+            );
+        }
+
+        for i in 0..10_000 {
+            let key_value = format!("{i:016}");
+            assert_eq!(
+                tree.insert(&key_value, (i + 1) as *mut ()).unwrap(),
+                InsertResult::Replaced(i as *mut ())
+            );
+            assert_eq!(
+                tree.insert_if_absent(&key_value, i as *mut ()).unwrap(),
+                InsertResult::Present((i + 1) as *mut ())
+            );
+        }
+
+        assert!(tree.values().eq((1..=10_000).map(|i| i as *mut ())));
+        assert_eq!(
+            tree.insert_if_absent("new", std::ptr::null_mut()).unwrap(),
+            InsertResult::Inserted
+        );
+    }
+
+    #[test]
+    fn entry() {
+        let mut tree: BTree<usize> = BTree::new();
+        let mut model = std::collections::HashMap::new();
+
+        for word in BEES.split_whitespace() {
+            tree.entry(word)
+                .and_modify(|count| *count = std::ptr::without_provenance_mut(count.addr() + 1))
+                .or_insert(std::ptr::without_provenance_mut(1))
+                .unwrap();
+
+            *model.entry(word).or_insert(0) += 1;
+        }
+
+        assert_eq!(tree.iter().count(), model.len());
+
+        for (word, count) in model {
+            assert_eq!(tree.get(word).unwrap(), Some(count as *mut usize));
+        }
+
+        let mut tree: BTree<()> = BTree::new();
+
+        // every insert goes through the vacant path, including all leaf and branch splits
+        for i in (0..10_000).rev() {
+            let key_value = format!("{i:016}");
+            let value = tree
+                .entry(&key_value)
+                .or_insert_with(|| i as *mut ())
+                .unwrap();
+            assert_eq!(value, i as *mut ());
+        }
+
+        assert!(tree.get_height() > 1);
+        assert!(tree.values().eq((0..10_000).map(|i| i as *mut ())));
+
+        let key_value = format!("{:016}", 42);
+        assert_eq!(
+            tree.entry(&key_value).insert(7 as *mut ()).unwrap(),
+            Some(42 as *mut ())
+        );
+        assert_eq!(tree.entry(&key_value).key(), key_value.as_bytes());
+        assert_eq!(tree.entry("missing").insert(7 as *mut ()).unwrap(), None);
+        assert_eq!(tree.get("missing").unwrap(), Some(7 as *mut ()));
+    }
+
+    #[test]
+    fn bulk_load() {
+        let keys: Vec<_> = (0..20_000).map(|i| format!("{i:016}")).collect();
+        let mut inserted = BTree::new();
+
+        for (i, key) in keys.iter().enumerate() {
+            inserted.insert(key, i as *mut ()).unwrap();
+        }
+
+        for fill_factor in [1.0, 0.7, 0.05] {
+            let entries = keys.iter().enumerate().map(|(i, key)| (key, i as *mut ()));
+            let mut tree: BTree<()> = BTree::from_sorted_iter(entries, fill_factor).unwrap();
+            tree.check_fences();
+
+            assert!(tree.keys().eq(keys.iter().map(String::as_bytes)));
+            assert!(tree
+                .keys()
+                .rev()
+                .eq(keys.iter().rev().map(String::as_bytes)));
+
+            for (i, key) in keys.iter().enumerate().step_by(7) {
+                assert_eq!(tree.get(key).unwrap(), Some(i as *mut ()));
+            }
+
+            if fill_factor == 1.0 {
+                assert!(tree.count_nodes() < inserted.count_nodes());
+            }
+
+            // the result has to behave like any other tree
+            for key in keys.iter().step_by(2) {
+                tree.remove(key);
+            }
+
+            tree.insert("0", std::ptr::null_mut()).unwrap();
+            assert_eq!(tree.iter().count(), keys.len() / 2 + 1);
+            tree.check_fences();
+        }
+
+        let empty = BTree::<()>::from_sorted_iter(Vec::<(&str, _)>::new(), 1.0).unwrap();
+        assert_eq!(empty.iter().count(), 0);
+    }
+
+    #[test]
+    fn bulk_load_long_separators() {
+        // every separator is longer than a branch may fill, so each branch gets the bare two
+        let prefix = "p".repeat(200);
+        let keys: Vec<_> = (0..200).map(|i| format!("{prefix}{i:04}")).collect();
+
+        for fill_factor in [0.05, 1.0] {
+            let entries = keys.iter().enumerate().map(|(i, key)| (key, i as *mut ()));
+            let tree: BTree<()> = BTree::from_sorted_iter(entries, fill_factor).unwrap();
+            tree.check_fences();
+
+            assert!(tree.keys().eq(keys.iter().map(String::as_bytes)));
+
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(tree.get(key).unwrap(), Some(i as *mut ()));
+            }
+        }
+    }
+
+    #[test]
+    fn map() {
+        // counts live values, so leaks and double frees both show up
+        #[derive(Debug)]
+        struct Tracked(u64, std::rc::Rc<std::cell::Cell<isize>>);
+
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                self.1.set(self.1.get() - 1);
+            }
+        }
+
+        let live = std::rc::Rc::new(std::cell::Cell::new(0));
+        let tracked = |n| {
+            live.set(live.get() + 1);
+            Tracked(n, live.clone())
+        };
+
+        let mut map = BTreeMap::<(String, i64), Tracked>::new();
+
+        for i in -2000..2000 {
+            assert!(map
+                .insert((format!("{}", i % 13), i), tracked(0))
+                .unwrap()
+                .is_none());
+        }
+
+        assert_eq!(map.len(), 4000);
+        assert_eq!(live.get(), 4000);
+
+        // overwrites hand back the old value and free nothing else
+        let old = map
+            .insert(("5".to_owned(), 5), tracked(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(old.0, 0);
+        drop(old);
+        assert_eq!(live.get(), 4000);
+
+        map.get_mut(&("5".to_owned(), 5)).unwrap().unwrap().0 += 1;
+        assert_eq!(map.get(&("5".to_owned(), 5)).unwrap().unwrap().0, 2);
+        assert!(map.get(&("5".to_owned(), 6)).unwrap().is_none());
+
+        for i in (-2000..2000).step_by(2) {
+            assert!(map.remove(&(format!("{}", i % 13), i)).is_some());
+        }
+
+        assert!(map.remove(&("0".to_owned(), 0)).is_none());
+        assert_eq!(map.len(), 2000);
+        assert_eq!(live.get(), 2000);
+
+        let keys: Vec<_> = map.keys().collect();
+        let mut expected: Vec<_> = (-2000i64..2000)
+            .filter(|i| i % 2 != 0)
+            .map(|i| (format!("{}", i % 13), i))
+            .collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        // a tuple range pins the first field and scans the second
+        let start = ("-3".to_owned(), i64::MIN);
+        let end = ("-3".to_owned(), i64::MAX);
+        assert!(map.range(start..=end).rev().map(|(key, _)| key).eq(expected
+            .iter()
+            .rev()
+            .filter(|(group, _)| group == "-3")
+            .cloned()));
+
+        drop(map);
+        assert_eq!(live.get(), 0);
+    }
+
+    #[test]
+    fn inline_values() {
+        let mut tree: BTree<()> = BTree::new();
+        let value = |i: usize, round: usize| vec![i as u8; (i * 7 + round) % 40];
+
+        for i in 0..5000 {
+            assert_eq!(
+                tree.insert_value(&format!("{i:05}"), &value(i, 0)).unwrap(),
+                None
+            );
+        }
+
+        // replacing with values of other sizes moves entries around and splits leaves
+        for i in (0..5000).step_by(3) {
+            assert_eq!(
+                tree.insert_value(&format!("{i:05}"), &value(i, 1)).unwrap(),
+                Some(value(i, 0))
+            );
+        }
+
+        for i in 0..5000 {
+            let round = usize::from(i % 3 == 0);
+            assert_eq!(
+                tree.get_value(&format!("{i:05}")).unwrap(),
+                Some(value(i, round).as_slice())
+            );
+        }
+
+        for i in (0..5000).step_by(2) {
+            let round = usize::from(i % 3 == 0);
+            assert_eq!(tree.remove_value(&format!("{i:05}")), Some(value(i, round)));
+        }
+
+        assert_eq!(tree.get_value("00000").unwrap(), None);
+
+        let entries: Vec<_> = tree
+            .range::<&[u8], RangeFull>(..)
+            .inline_values()
+            .rev()
+            .collect();
+
+        assert_eq!(entries.len(), 2500);
+        assert_eq!(entries[0], (b"04999"[..].into(), value(4999, 0).as_slice()));
+        assert_eq!(entries[2499], (b"00001"[..].into(), value(1, 0).as_slice()));
+
+        // the largest values still leave room for a balanced split
+        let big = vec![0xAB; MAX_VALUE_LEN];
+
+        for i in 0..200 {
+            tree.insert_value(&format!("big{i:03}"), &big).unwrap();
+        }
+
+        for i in 0..200 {
+            assert_eq!(
+                tree.get_value(&format!("big{i:03}")).unwrap(),
+                Some(big.as_slice())
+            );
+        }
+    }
+
+    #[test]
+    fn pointer_api_on_inline_values() {
+        let mut tree: BTree<()> = BTree::new();
+
+        for i in 0..1000 {
+            tree.insert(&format!("{i:04}"), i as *mut ()).unwrap();
+        }
+
+        tree.insert_value("0500", b"abc").unwrap();
+
+        // the pointer API doesn't see values that aren't pointers, instead of panicking on them
+        assert_eq!(tree.get("0500").unwrap(), None);
+        assert_eq!(tree.iter().count(), 999);
+        assert_eq!(tree.values().rev().count(), 999);
+        assert!(tree.keys().all(|key| key != &b"0500"[..]));
+        assert_eq!(tree.remove("0500"), None);
+        assert_eq!(tree.get_value("0500").unwrap(), Some(&b"abc"[..]));
+
+        assert_eq!(
+            tree.insert("0500", 7 as *mut ()),
+            Err(BTreeError::ValueSize { len: 3 })
+        );
+        assert_eq!(
+            tree.entry("0500").or_insert(7 as *mut ()),
+            Err(BTreeError::ValueSize { len: 3 })
+        );
+
+        let mut cursor = tree.cursor();
+        cursor.seek("0500");
+        assert_eq!(cursor.value(), None);
+        assert_eq!(cursor.set_value(7 as *mut ()), None);
+
+        assert_eq!(tree.get_value("0500").unwrap(), Some(&b"abc"[..]));
+        assert_eq!(tree.remove_value("0500"), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn errors() {
+        let mut tree: BTree<()> = BTree::new();
+        let too_big = vec![0; MAX_VALUE_LEN + 1];
+
+        assert_eq!(
+            tree.insert_value("key", &too_big),
+            Err(BTreeError::ValueTooLarge {
+                len: MAX_VALUE_LEN + 1
+            })
+        );
+        assert_eq!(tree.get_value("key"), Ok(None));
+
+        for i in 0..10_000 {
+            tree.insert(&format!("{i:016}"), i as *mut ()).unwrap();
+        }
+
+        // a branch that lost its rightmost child can't be descended into
+        let root = unsafe { &mut *(tree.root() as *mut SlottedBranch<()>) };
+        let last = root.data.head_mut().pointer.take();
+
+        let key = format!("{:016}", 9_999);
+        assert_eq!(tree.get(&key), Err(BTreeError::InvalidLayout));
+        assert_eq!(
+            tree.insert(&key, std::ptr::null_mut()),
+            Err(BTreeError::InvalidLayout)
+        );
+
+        // ranges towards it end before they start and say why, removing finds nothing there
+        let range = tree.range(&key[..]..);
+        assert_eq!(range.error(), Some(&BTreeError::InvalidLayout));
+        assert_eq!(range.count(), 0);
+        assert_eq!(tree.prefix(&key).count(), 0);
+        assert_eq!(tree.remove(&key), None);
+
+        let mut cursor = tree.cursor();
+        assert!(!cursor.seek(&key));
+        assert!(!cursor.seek_last());
+        assert!(cursor.seek_first());
+
+        let tree = Box::into_raw(Box::new(tree));
+        let mut found = std::ptr::null_mut();
+
+        let res = unsafe { ffi_btree_get_with_status(tree, key.as_ptr(), key.len(), &mut found) };
+        assert_eq!(res, FfiGetResult::InvalidLayout);
+
+        let res = unsafe {
+            ffi_btree_insert_with_result(tree, key.as_ptr(), key.len(), found, std::ptr::null_mut())
+        };
+        assert_eq!(res, FfiInsertResult::InvalidLayout);
+
+        // the other keys are still there
+        let res =
+            unsafe { ffi_btree_get_with_status(tree, "0000000000000042".as_ptr(), 16, &mut found) };
+        assert_eq!((res, found), (FfiGetResult::Found, 42 as *mut ()));
+
+        unsafe { &mut *((*tree).root() as *mut SlottedBranch<()>) }
+            .data
+            .head_mut()
+            .pointer = last;
+        ffi_btree_drop(tree);
+    }
+
+    #[test]
+    fn large_keys() {
+        let mut tree: BTree<()> = BTree::new();
+
+        // every length from nothing to past the inline limit, then up to a few pages
+        let lengths =
+            (0..600)
+                .chain((600..9000).step_by(97))
+                .chain([16 * 1024, 32 * 1024, 64 * 1024]);
+
+        let mut keys: Vec<Vec<u8>> = lengths
+            .enumerate()
+            .map(|(i, len)| (0..len).map(|j| (j * 31 + i) as u8).collect())
+            .collect();
+
+        // long keys that only differ at the very end make for long separators in the branches
+        for i in 0..200 {
+            let mut key = vec![b'x'; 10_000];
+            key.extend(format!("{i:03}").bytes());
+            keys.push(key);
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(
+                tree.insert(key, std::ptr::without_provenance_mut(i))
+                    .unwrap(),
+                InsertResult::Inserted
+            );
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(
+                tree.get(key).unwrap(),
+                Some(std::ptr::without_provenance_mut(i))
+            );
+        }
+
+        let mut sorted = keys.clone();
+        sorted.sort();
+
+        assert!(tree.get_height() > 0);
+        assert!(tree.keys().eq(sorted.iter().map(Vec::as_slice)));
+        assert!(tree.keys().rev().eq(sorted.iter().rev().map(Vec::as_slice)));
+        assert_eq!(tree.prefix(&vec![b'x'; 10_000]).count(), 200);
+
+        for key in keys.iter().step_by(2) {
+            assert!(tree.remove(key).is_some());
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            let expected = (i % 2 == 1).then(|| std::ptr::without_provenance_mut(i));
+            assert_eq!(tree.get(key).unwrap(), expected);
+        }
+
+        // long keys with inline values
+        let mut tree: BTree<()> = BTree::new();
+
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert_value(key, &i.to_be_bytes()).unwrap();
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(tree.get_value(key).unwrap(), Some(&i.to_be_bytes()[..]));
+        }
+    }
+
+    #[test]
+    fn bulk_load_errors() {
+        let null = std::ptr::null_mut::<()>();
+
+        let res = BTree::<()>::from_sorted_iter([("a", null), ("c", null), ("b", null)], 1.0);
+        assert_eq!(res.unwrap_err(), BulkLoadError::Unsorted { index: 2 });
+
+        let res = BTree::<()>::from_sorted_iter([("a", null), ("b", null), ("b", null)], 1.0);
+        assert_eq!(res.unwrap_err(), BulkLoadError::Duplicate { index: 2 });
+
+        let res = BTree::<()>::from_sorted_iter([("a", null)], 1.5);
+        assert_eq!(res.unwrap_err(), BulkLoadError::FillFactor);
+    }
+
+    #[test]
+    fn ffi_insert() {
+        let tree = ffi_btree_new();
+        let mut existing = std::ptr::null_mut();
+
+        let res = unsafe {
+            ffi_btree_insert_with_result(tree, "key".as_ptr(), 3, 11 as *mut (), &mut existing)
+        };
+        assert_eq!(res, FfiInsertResult::Inserted);
+
+        let res = unsafe {
+            ffi_btree_insert_with_result(tree, "key".as_ptr(), 3, 12 as *mut (), &mut existing)
+        };
+        assert_eq!((res, existing), (FfiInsertResult::Replaced, 11 as *mut ()));
+
+        let res = unsafe {
+            ffi_btree_insert_if_absent(tree, "key".as_ptr(), 3, 13 as *mut (), &mut existing)
+        };
+        assert_eq!((res, existing), (FfiInsertResult::Present, 12 as *mut ()));
+
+        // the plain insert keeps its old signature and overwrites as well
+        unsafe { ffi_btree_insert(tree, "other".as_ptr(), 5, 14 as *mut ()) };
+        unsafe { ffi_btree_insert(tree, "other".as_ptr(), 5, 15 as *mut ()) };
+
+        let found = unsafe { ffi_btree_get(tree, "other".as_ptr(), 5) };
+        assert_eq!(found, 15 as *mut ());
+
+        let mut found = std::ptr::null_mut();
+        let res = unsafe { ffi_btree_get_with_status(tree, "key".as_ptr(), 3, &mut found) };
+        assert_eq!((res, found), (FfiGetResult::Found, 12 as *mut ()));
+
+        let res = unsafe { ffi_btree_get_with_status(tree, "missing".as_ptr(), 7, &mut found) };
+        assert_eq!(res, FfiGetResult::Missing);
+        assert!(unsafe { ffi_btree_get(tree, "missing".as_ptr(), 7) }.is_null());
 
-        assert_eq!(tree.get("hello"), Some(location));
+        let binary = [0xFF, 0x00, 0xC3];
+        unsafe { ffi_btree_insert(tree, binary.as_ptr(), 3, 16 as *mut ()) };
+        let found = unsafe { ffi_btree_get(tree, binary.as_ptr(), 3) };
+        assert_eq!(found, 16 as *mut ());
+
+        ffi_btree_drop(tree);
     }
 
     #[test]
@@ -43,13 +564,60 @@ mod btree_test {
 
         for i in 0..10_000 {
             let key_value = format!("{i:016}");
-            tree.insert(&key_value, i as *mut ());
+            tree.insert(&key_value, i as *mut ()).unwrap();
         }
 
         for i in 0..1_000 {
             let key_value = format!("{i:016}");
-            assert_eq!(tree.get(&key_value), Some(i as *mut ()));
+            assert_eq!(tree.get(&key_value).unwrap(), Some(i as *mut ()));
+        }
+    }
+
+    #[test]
+    fn binary_keys() {
+        let mut tree = BTree::new();
+        let mut keys = vec![];
+
+        // big endian integers sort numerically, and are full of zero and 0xFF bytes
+        for i in 0..10_000u64 {
+            let key = (i * 0x0101_0101_0101).to_be_bytes();
+            tree.insert(&key, i as *mut ()).unwrap();
+            keys.push(key.to_vec());
+        }
+
+        // random ids, none of which are valid UTF-8 in all likelihood
+        for i in 10_000..20_000 {
+            let key = rand::random::<[u8; 16]>();
+            tree.insert(&key, i as *mut ()).unwrap();
+            keys.push(key.to_vec());
+        }
+
+        // prefixes of each other, which only differ by trailing zeroes
+        for len in 0..8 {
+            let key = vec![0; len];
+            tree.insert(&key, len as *mut ()).unwrap();
+            keys.push(key);
         }
+
+        keys.sort();
+        keys.dedup();
+
+        assert!(tree.keys().eq(keys.iter().map(Vec::as_slice)));
+        assert_eq!(tree.get(&[0u8; 3]).unwrap(), Some(3 as *mut ()));
+        assert_eq!(tree.get(&[0u8; 9]).unwrap(), None);
+        assert_eq!(
+            tree.get(&42u64.wrapping_mul(0x0101_0101_0101).to_be_bytes())
+                .unwrap(),
+            Some(42 as *mut ())
+        );
+
+        let start = 100u64 * 0x0101_0101_0101;
+        let end = 200u64 * 0x0101_0101_0101;
+        let values = tree
+            .range(start.to_be_bytes()..end.to_be_bytes())
+            .map(|(_, value)| value as usize);
+
+        assert!(values.eq(100..200));
     }
 
     #[test]
@@ -63,42 +631,1006 @@ mod btree_test {
         }
 
         for line in set.iter() {
-            tree.insert(line, line.as_ptr() as *mut u8);
+            tree.insert(line, line.as_ptr() as *mut u8).unwrap();
         }
 
         for line in set.iter() {
-            let entry = tree.get(line);
+            let entry = tree.get(line).unwrap();
             assert_eq!(entry, Some(line.as_ptr() as *mut u8))
         }
     }
 
     #[test]
-    fn random() {
-        let mut strings = vec![];
+    fn remove() {
+        let mut tree = BTree::new();
 
-        let chars: Vec<_> = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.,/#?!"
-            .chars()
-            .collect();
+        for i in 0..10_000 {
+            let key_value = format!("{i:016}");
+            tree.insert(&key_value, i as *mut ()).unwrap();
+        }
 
-        for _ in 0..10_000 {
-            let len = rand::random_range(0..128);
-            let mut str = String::new();
+        for i in (0..10_000).step_by(2) {
+            let key_value = format!("{i:016}");
+            assert_eq!(tree.remove(&key_value), Some(i as *mut ()));
+        }
 
-            for _ in 0..len {
-                str.push(chars[rand::random_range(0..chars.len())]);
-            }
+        tree.check_fences();
 
-            strings.push(str);
+        for i in 0..10_000 {
+            let key_value = format!("{i:016}");
+            let expected = (i % 2 == 1).then_some(i as *mut ());
+            assert_eq!(tree.get(&key_value).unwrap(), expected);
         }
 
-        let mut tree: BTree<()> = BTree::new();
+        for i in (1..10_000).step_by(2) {
+            let key_value = format!("{i:016}");
+            assert_eq!(tree.remove(&key_value), Some(i as *mut ()));
+        }
 
-        for string in &strings {
-            tree.insert(string, std::ptr::null_mut());
+        assert_eq!(tree.get_height(), 0);
+        assert_eq!(tree.remove(&format!("{:016}", 1)), None);
+    }
+
+    #[test]
+    fn remove_random() {
+        let mut lines: Vec<_> = BEES.lines().collect::<HashSet<_>>().into_iter().collect();
+        let mut tree = BTree::new();
+
+        for line in &lines {
+            tree.insert(line, line.as_ptr() as *mut u8).unwrap();
         }
 
-        for string in &strings {
-            assert_ne!(tree.get(string), None)
+        // HashSet iteration order is random enough for our purposes
+        let removed = lines.split_off(lines.len() / 3);
+
+        for line in &removed {
+            assert_eq!(tree.remove(line), Some(line.as_ptr() as *mut u8));
+        }
+
+        for line in &removed {
+            assert_eq!(tree.get(line).unwrap(), None);
+        }
+
+        tree.check_fences();
+
+        for line in &lines {
+            assert_eq!(tree.get(line).unwrap(), Some(line.as_ptr() as *mut u8));
+        }
+    }
+
+    #[test]
+    fn range() {
+        let mut lines: Vec<_> = BEES.lines().collect::<HashSet<_>>().into_iter().collect();
+        let mut tree = BTree::new();
+
+        for line in &lines {
+            tree.insert(line, line.as_ptr() as *mut u8).unwrap();
+        }
+
+        lines.sort();
+
+        let bounds = [
+            (Bound::Included("B"), Bound::Excluded("M")),
+            (Bound::Excluded("Barry!"), Bound::Included("Yes.")),
+            (Bound::Unbounded, Bound::Excluded("Hello?")),
+            (Bound::Included("Oh"), Bound::Unbounded),
+            (Bound::Included("Z"), Bound::Excluded("A")),
+            (Bound::Unbounded, Bound::Unbounded),
+        ];
+
+        for bound in bounds {
+            let expected: Vec<_> = lines
+                .iter()
+                .filter(|line| bound.contains(*line))
+                .map(|line| (line.as_bytes().into(), line.as_ptr() as *mut u8))
+                .collect();
+
+            let actual: Vec<_> = tree.range::<&str, _>(bound).collect();
+
+            assert_eq!(actual, expected);
+        }
+
+        let actual: Vec<_> = tree.range("Hello?"..="Hello?").collect();
+        assert_eq!(
+            actual,
+            vec![(b"Hello?"[..].into(), tree.get("Hello?").unwrap().unwrap())]
+        );
+    }
+
+    #[test]
+    fn iter() {
+        let mut tree = BTree::new();
+        let mut keys = vec![];
+
+        for i in (0..10_000).rev() {
+            let key_value = format!("{i:016}");
+            tree.insert(&key_value, i as *mut ()).unwrap();
+            keys.push(key_value);
+        }
+
+        keys.sort();
+
+        assert!(tree.keys().eq(keys.iter().map(String::as_bytes)));
+        assert!(tree.values().eq((0..10_000).map(|i| i as *mut ())));
+
+        let mut count = 0;
+        for (key, value) in &tree {
+            assert_eq!(key, format!("{:016}", value as usize).as_bytes());
+            count += 1;
+        }
+
+        assert_eq!(count, 10_000);
+        assert_eq!(BTree::<()>::new().iter().next(), None);
+    }
+
+    #[test]
+    fn reverse() {
+        let mut lines: Vec<_> = BEES.lines().collect::<HashSet<_>>().into_iter().collect();
+        let mut tree = BTree::new();
+
+        for line in &lines {
+            tree.insert(line, line.as_ptr() as *mut u8).unwrap();
         }
+
+        lines.sort();
+
+        assert!(tree
+            .keys()
+            .rev()
+            .eq(lines.iter().rev().map(|line| line.as_bytes())));
+
+        let expected: Vec<_> = lines
+            .iter()
+            .filter(|line| ("B".."M").contains(*line))
+            .rev()
+            .collect();
+
+        assert!(tree
+            .range("B".."M")
+            .rev()
+            .map(|(key, _)| key)
+            .eq(expected.into_iter().map(|line| line.as_bytes())));
+
+        // eat from both ends, which have to meet in the middle without skipping or repeating
+        let mut model = &lines[..];
+        let mut iter = tree.iter();
+
+        for i in 0.. {
+            let (actual, expected) = if rand::random_bool(0.5) {
+                let expected = model.split_first().map(|(first, rest)| {
+                    model = rest;
+                    first
+                });
+                (iter.next(), expected)
+            } else {
+                let expected = model.split_last().map(|(last, rest)| {
+                    model = rest;
+                    last
+                });
+                (iter.next_back(), expected)
+            };
+
+            assert_eq!(
+                actual.as_ref().map(|(key, _)| key.as_ref()),
+                expected.map(|line| line.as_bytes()),
+                "step {i}"
+            );
+
+            if actual.is_none() {
+                break;
+            }
+        }
+
+        drop(iter);
+
+        // removing makes leaves shrink and merge, which must keep the back links intact
+        for (_, line) in lines.iter().enumerate().filter(|(i, _)| i % 4 != 0) {
+            tree.remove(line);
+        }
+
+        let remaining = lines
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 4 == 0)
+            .map(|(_, line)| line.as_bytes());
+
+        assert!(tree.keys().rev().eq(remaining.rev()));
+    }
+
+    #[test]
+    fn cursor() {
+        let mut tree = BTree::new();
+
+        for i in (0..10_000).step_by(2) {
+            let key_value = format!("{i:016}");
+            tree.insert(&key_value, i as *mut ()).unwrap();
+        }
+
+        let mut cursor = tree.cursor();
+        assert_eq!(cursor.key(), None);
+
+        assert!(cursor.seek(&format!("{:016}", 4001)));
+        assert_eq!(cursor.value(), Some(4002 as *mut ()));
+
+        assert!(cursor.prev());
+        assert!(cursor.prev());
+        assert_eq!(cursor.value(), Some(3998 as *mut ()));
+
+        // walk across a couple of leaves and back again
+        for _ in 0..1_000 {
+            assert!(cursor.next());
+        }
+        assert_eq!(cursor.value(), Some(5998 as *mut ()));
+
+        for _ in 0..1_000 {
+            assert!(cursor.prev());
+        }
+        assert_eq!(cursor.value(), Some(3998 as *mut ()));
+
+        assert_eq!(cursor.set_value(42 as *mut ()), Some(3998 as *mut ()));
+
+        assert!(cursor.seek_last());
+        assert_eq!(
+            cursor.key(),
+            Some(format!("{:016}", 9998).as_bytes().into())
+        );
+        assert!(!cursor.next());
+        assert_eq!(cursor.key(), None);
+
+        assert!(cursor.seek_first());
+        assert!(!cursor.prev());
+        assert!(!cursor.seek(&format!("{:016}", 9999)));
+
+        assert_eq!(
+            tree.get(&format!("{:016}", 3998)).unwrap(),
+            Some(42 as *mut ())
+        );
+    }
+
+    #[test]
+    fn cursor_merge_join() {
+        let mut left: BTree<()> = BTree::new();
+        let mut right: BTree<()> = BTree::new();
+
+        for i in 0..5_000 {
+            left.insert(&format!("{:016}", i * 2), std::ptr::null_mut())
+                .unwrap();
+            right
+                .insert(&format!("{:016}", i * 3), std::ptr::null_mut())
+                .unwrap();
+        }
+
+        let mut left_cursor = left.cursor();
+        let mut right_cursor = right.cursor();
+        let mut joined = vec![];
+
+        let mut valid = left_cursor.seek_first() && right_cursor.seek_first();
+
+        while valid {
+            let (left_key, right_key) = (left_cursor.key().unwrap(), right_cursor.key().unwrap());
+
+            if left_key < right_key {
+                valid = left_cursor.next();
+            } else if right_key < left_key {
+                valid = right_cursor.next();
+            } else {
+                joined.push(left_key.into_owned());
+                valid = left_cursor.next() && right_cursor.next();
+            }
+        }
+
+        let expected: Vec<_> = (0..10_000)
+            .step_by(6)
+            .map(|i| format!("{i:016}").into_bytes())
+            .collect();
+
+        assert_eq!(joined, expected);
+    }
+
+    #[test]
+    fn prefix() {
+        let mut tree = BTree::new();
+        let mut keys = vec![];
+
+        for tenant in ["acme", "ac", "globex", "initech"] {
+            for table in ["orders", "order", "users"] {
+                for row in 0..200 {
+                    keys.push(format!("{tenant}/{table}/{row}"));
+                }
+            }
+        }
+
+        for key in &keys {
+            tree.insert(key, std::ptr::null_mut::<()>()).unwrap();
+        }
+
+        keys.sort();
+
+        for prefix in [
+            "acme/",
+            "acme/order",
+            "acme/orders/",
+            "globex/users/19",
+            "ac",
+            "",
+            "zzz",
+        ] {
+            let expected = keys
+                .iter()
+                .filter(|key| key.starts_with(prefix))
+                .map(String::as_bytes);
+            assert!(tree.prefix(prefix).map(|(key, _)| key).eq(expected));
+        }
+
+        assert_eq!(tree.prefix("acme/orders/1").count(), 111);
+    }
+
+    #[test]
+    fn split_window() {
+        let keys: Vec<_> = (0..20_000u64)
+            .map(|i| {
+                let user = i.wrapping_mul(0x9E37_79B9_7F4A_7C15) % 100_000;
+                format!("https://example.com/users/{user}/settings/notifications/{i}")
+            })
+            .collect();
+
+        let build = |window| {
+            let mut tree = BTree::new();
+            tree.set_split_window(window);
+
+            for (i, key) in keys.iter().enumerate() {
+                tree.insert(key, i as *mut ()).unwrap();
+            }
+
+            tree.check_fences();
+
+            for (i, key) in keys.iter().enumerate().step_by(7) {
+                assert_eq!(tree.get(key).unwrap(), Some(i as *mut ()));
+            }
+
+            tree
+        };
+
+        let before = build(0).fan_out();
+        let after = build(8).fan_out();
+
+        // shorter separators leave room for more of them in every branch
+        assert!(after.average() > before.average());
+    }
+
+    #[test]
+    fn random() {
+        let mut strings = vec![];
+
+        let chars: Vec<_> = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.,/#?!"
+            .chars()
+            .collect();
+
+        for _ in 0..10_000 {
+            let len = rand::random_range(0..128);
+            let mut str = String::new();
+
+            for _ in 0..len {
+                str.push(chars[rand::random_range(0..chars.len())]);
+            }
+
+            strings.push(str);
+        }
+
+        let mut tree: BTree<()> = BTree::new();
+
+        for string in &strings {
+            tree.insert(string, std::ptr::null_mut()).unwrap();
+        }
+
+        for string in &strings {
+            assert_ne!(tree.get(string).unwrap(), None)
+        }
+
+        tree.check_fences();
+    }
+
+    fn random_with_page_size<const P: usize>() {
+        let mut strings = HashSet::new();
+
+        while strings.len() < 5_000 {
+            let len = rand::random_range(0..200);
+            strings.insert(
+                (0..len)
+                    .map(|_| rand::random_range(b'a'..=b'z') as char)
+                    .collect::<String>(),
+            );
+        }
+
+        let mut tree = BTree::<(), P>::with_page_size();
+
+        for (i, string) in strings.iter().enumerate() {
+            tree.insert(string, i as *mut ()).unwrap();
+        }
+
+        tree.check_fences();
+
+        for (i, string) in strings.iter().enumerate() {
+            assert_eq!(tree.get(string).unwrap(), Some(i as *mut ()));
+        }
+
+        for (i, string) in strings.iter().enumerate().step_by(2) {
+            assert_eq!(tree.remove(string), Some(i as *mut ()));
+        }
+
+        tree.check_fences();
+
+        for (i, string) in strings.iter().enumerate() {
+            let expected = (i % 2 == 1).then_some(i as *mut ());
+            assert_eq!(tree.get(string).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn other_page_sizes() {
+        random_with_page_size::<1024>();
+        random_with_page_size::<16384>();
+        random_with_page_size::<65536>();
+    }
+
+    #[test]
+    fn page_pool() {
+        let pool = PagePool::<4096>::new();
+        let pages =
+            |tree: &BTree<(), 4096, &PagePool>| tree.count_nodes() + tree.fan_out().branches;
+
+        let mut tree = BTree::new_in(&pool);
+
+        for i in 0..20_000 {
+            tree.insert(&format!("{i:08}"), i as *mut ()).unwrap();
+        }
+
+        assert_eq!(pool.used_pages(), pages(&tree));
+        assert_eq!(tree.root() as usize % 4096, 0);
+
+        let reserved = pool.reserved_pages();
+
+        for i in 0..20_000 {
+            assert_eq!(tree.remove(&format!("{i:08}")), Some(i as *mut ()));
+        }
+
+        assert_eq!(pool.used_pages(), pages(&tree));
+
+        // freed pages are handed out again before the pool reserves more
+        for i in 0..20_000 {
+            tree.insert(&format!("{i:08}"), i as *mut ()).unwrap();
+        }
+
+        assert_eq!(pool.reserved_pages(), reserved);
+
+        // trees can share a pool
+        let other = BTree::<(), 4096, _>::from_sorted_iter_in(
+            (0..1000).map(|i| (format!("{i:08}"), i as *mut ())),
+            1.0,
+            &pool,
+        )
+        .unwrap();
+
+        assert_eq!(pool.used_pages(), pages(&tree) + pages(&other));
+
+        drop(tree);
+        drop(other);
+
+        assert_eq!(pool.used_pages(), 0);
+    }
+
+    #[test]
+    fn out_of_pages() {
+        let pool = PagePool::<4096>::with_limit(8);
+        let mut tree = BTree::<(), 4096, _>::new_in(&pool);
+        let mut inserted = 0;
+
+        let err = loop {
+            match tree.insert(&format!("{inserted:08}"), inserted as *mut ()) {
+                Ok(_) => inserted += 1,
+                Err(err) => break err,
+            }
+        };
+
+        assert_eq!(err, BTreeError::OutOfPages);
+        tree.check_fences();
+
+        // the failed insert left the tree as it was
+        for i in 0..inserted {
+            assert_eq!(tree.get(&format!("{i:08}")).unwrap(), Some(i as *mut ()));
+        }
+
+        assert_eq!(tree.get(&format!("{inserted:08}")).unwrap(), None);
+        assert_eq!(tree.iter().count(), inserted);
+
+        // merges give pages back, which makes room for inserts again
+        for i in 0..inserted / 2 {
+            assert_eq!(tree.remove(&format!("{i:08}")), Some(i as *mut ()));
+        }
+
+        tree.insert(&format!("{inserted:08}"), inserted as *mut ())
+            .unwrap();
+        tree.check_fences();
+
+        // a bulk load that runs out hands back the pages it took, whether that happens in the leaves
+        // or in the branches above them
+        let entries = || (0..10_000).map(|i| (format!("{i:08}"), i as *mut ()));
+        let leaves = BTree::<()>::from_sorted_iter(entries(), 1.0)
+            .unwrap()
+            .count_nodes();
+
+        for limit in [4, leaves] {
+            let small = PagePool::<4096>::with_limit(limit);
+            let res = BTree::<(), 4096, _>::from_sorted_iter_in(entries(), 1.0, &small);
+
+            assert_eq!(res.err(), Some(BulkLoadError::OutOfPages));
+            assert_eq!(small.used_pages(), 0);
+        }
+    }
+
+    #[test]
+    fn overflow_pages() {
+        let pool = PagePool::<4096>::new();
+        let pages =
+            |tree: &BTree<(), 4096, &PagePool>| tree.count_nodes() + tree.fan_out().branches;
+        let key = |i: usize| format!("{i:05000}");
+
+        let mut tree = BTree::new_in(&pool);
+
+        for i in 0..500 {
+            tree.insert(&key(i), i as *mut ()).unwrap();
+        }
+
+        tree.check_fences();
+
+        // two overflow pages for each key, and more for the separators
+        assert!(pool.used_pages() >= pages(&tree) + 2 * 500);
+
+        for i in 0..500 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(i as *mut ()));
+        }
+
+        for i in 0..500 {
+            assert_eq!(tree.remove(&key(i)), Some(i as *mut ()));
+        }
+
+        assert_eq!(pool.used_pages(), pages(&tree));
+        drop(tree);
+        assert_eq!(pool.used_pages(), 0);
+
+        // running out of pages for a long key leaves the tree as it was
+        let pool = PagePool::<4096>::with_limit(16);
+        let mut tree = BTree::<(), 4096, _>::new_in(&pool);
+        let mut inserted = 0;
+
+        let err = loop {
+            match tree.insert(&key(inserted), inserted as *mut ()) {
+                Ok(_) => inserted += 1,
+                Err(err) => break err,
+            }
+        };
+
+        assert_eq!(err, BTreeError::OutOfPages);
+        tree.check_fences();
+
+        for i in 0..inserted {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(i as *mut ()));
+        }
+
+        assert_eq!(tree.get(&key(inserted)).unwrap(), None);
+
+        drop(tree);
+        assert_eq!(pool.used_pages(), 0);
+    }
+
+    #[test]
+    fn arena() {
+        let arena = Arena::<4096>::new(10_000);
+
+        let mut tree = BTree::<(), 4096, _>::new_in(&arena);
+
+        for i in 0..20_000 {
+            tree.insert(&format!("{i:08}"), i as *mut ()).unwrap();
+        }
+
+        for i in 0..20_000 {
+            assert_eq!(tree.get(&format!("{i:08}")).unwrap(), Some(i as *mut ()));
+        }
+
+        let used = arena.used_pages();
+        assert_eq!(used, tree.count_nodes() + tree.fan_out().branches);
+
+        // the pages are left for the arena to free
+        drop(tree);
+        assert_eq!(arena.used_pages(), used);
+
+        // long keys get overflow pages from the arena too, one for each of them here
+        let mut tree = BTree::<(), 4096, _>::new_in(&arena);
+
+        for i in 0..2_000 {
+            tree.insert(&format!("{i:0300}"), i as *mut ()).unwrap();
+        }
+
+        let with_long = arena.used_pages();
+        assert!(with_long >= used + tree.count_nodes() + 2_000);
+
+        drop(tree);
+        assert_eq!(arena.used_pages(), with_long);
+
+        let mut owned = BTree::<(), 4096, _>::new_in(Arena::<4096>::new(100));
+
+        for i in 0..1_000 {
+            owned.insert(&format!("{i:08}"), i as *mut ()).unwrap();
+        }
+
+        assert!(owned.allocator().used_pages() > 1);
+    }
+
+    fn data_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("btree-{}-{name}", std::process::id()))
+    }
+
+    // every tenth key is long enough to live in overflow pages, and to be cut short as a fence
+    fn file_key(i: usize) -> String {
+        match i % 10 {
+            0 => format!("{i:0300}"),
+            _ => format!("{i:08}"),
+        }
+    }
+
+    fn check_file_tree<const P: usize, A: PageAllocator>(
+        tree: &BTree<(), P, A>,
+        keys: impl Iterator<Item = usize>,
+    ) {
+        let mut expected: Vec<_> = keys
+            .map(|i| (file_key(i).into_bytes(), format!("{i}").into_bytes()))
+            .collect();
+        expected.sort();
+
+        for (key, value) in &expected {
+            assert_eq!(tree.get_value(key).unwrap(), Some(value.as_slice()));
+        }
+
+        // walked backwards too, so the back links between leaves are checked as well
+        let entries = || tree.range::<&[u8], RangeFull>(..).inline_values();
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(key, value)| (key.into(), value.as_slice()))
+            .collect();
+
+        assert_eq!(entries().collect::<Vec<_>>(), expected);
+        assert!(entries().rev().eq(expected.into_iter().rev()));
+
+        tree.check_fences();
+    }
+
+    #[test]
+    fn file_tree() {
+        let path = data_file("file_tree");
+
+        let mut file = FileTree::<4096>::create(&path).unwrap();
+
+        for i in 0..5_000 {
+            let tree = file.tree_mut();
+            tree.insert_value(&file_key(i), format!("{i}").as_bytes())
+                .unwrap();
+        }
+
+        assert!(file.flush().unwrap() > 1);
+        drop(file);
+
+        let mut file = FileTree::<4096>::open(&path).unwrap();
+        assert!(file.tree().get_height() > 0);
+        check_file_tree(file.tree(), 0..5_000);
+
+        // nothing changed since it was read
+        assert_eq!(file.flush().unwrap(), 0);
+
+        // a value of the same length is overwritten in place, only its leaf is dirty
+        file.tree_mut()
+            .insert_value(&file_key(1234), b"4321")
+            .unwrap();
+        assert_eq!(file.flush().unwrap(), 1);
+        file.tree_mut()
+            .insert_value(&file_key(1234), b"1234")
+            .unwrap();
+
+        for i in 1_000..5_000 {
+            file.tree_mut().remove_value(&file_key(i)).unwrap();
+        }
+
+        file.flush().unwrap();
+        let free = file.free_pages();
+        assert!(free > 0);
+        drop(file);
+
+        let mut file = FileTree::<4096>::open(&path).unwrap();
+        check_file_tree(file.tree(), 0..1_000);
+        assert_eq!(file.free_pages(), free);
+
+        // freed pages are used again before the file grows
+        let pages = file.pages();
+
+        for i in 1_000..2_000 {
+            let tree = file.tree_mut();
+            tree.insert_value(&file_key(i), format!("{i}").as_bytes())
+                .unwrap();
+        }
+
+        file.flush().unwrap();
+        assert!(file.free_pages() < free);
+        assert_eq!(file.pages(), pages);
+        drop(file);
+
+        let mut file = FileTree::<4096>::open(&path).unwrap();
+        check_file_tree(file.tree(), 0..2_000);
+        drop(file);
+
+        let err = FileTree::<16384>::open(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_tree_page_size() {
+        let path = data_file("file_tree_page_size");
+
+        let mut file = FileTree::<16384>::create(&path).unwrap();
+        file.tree_mut().set_split_window(4);
+
+        for i in 0..10_000 {
+            let tree = file.tree_mut();
+            tree.insert_value(&file_key(i), format!("{i}").as_bytes())
+                .unwrap();
+        }
+
+        drop(file);
+
+        let mut file = FileTree::<16384>::open(&path).unwrap();
+        assert_eq!(file.tree().split_window(), 4);
+        check_file_tree(file.tree(), 0..10_000);
+        drop(file);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_tree_eviction() {
+        let path = data_file("file_tree_eviction");
+        let cache = 64;
+
+        // keys spread over several overflow pages each, which come and go with their nodes
+        let key = |i: usize| match i % 50 {
+            0 => format!("{i:05000}"),
+            _ => file_key(i),
+        };
+
+        let mut file = FileTree::<4096>::create(&path).unwrap();
+        file.set_cache_size(cache);
+
+        for i in 0..20_000 {
+            let tree = file.tree_mut();
+            tree.insert_value(&key(i), format!("{i}").as_bytes())
+                .unwrap();
+        }
+
+        file.flush().unwrap();
+        assert!(file.cached_pages() <= cache);
+        assert!(file.pages() > 10 * cache as u64);
+        drop(file);
+
+        // nothing is read before the tree gets to it
+        let mut file = FileTree::<4096>::open(&path).unwrap();
+        assert_eq!(file.cached_pages(), 0);
+        file.set_cache_size(cache);
+
+        assert_eq!(file.tree().get_value(&key(50)).unwrap(), Some(&b"50"[..]));
+        assert!(file.cached_pages() > file.tree().get_height());
+        assert!(file.cached_pages() <= cache);
+
+        for i in (0..20_000).step_by(2) {
+            assert_eq!(
+                file.tree_mut().remove_value(&key(i)),
+                Some(format!("{i}").into_bytes())
+            );
+        }
+
+        file.flush().unwrap();
+        assert!(file.cached_pages() <= cache);
+        drop(file);
+
+        let mut file = FileTree::<4096>::open(&path).unwrap();
+
+        for i in 0..20_000 {
+            let expected = format!("{i}");
+            let expected = (i % 2 == 1).then_some(expected.as_bytes());
+            assert_eq!(file.tree().get_value(&key(i)).unwrap(), expected);
+        }
+
+        let entries = file.tree().range::<&[u8], RangeFull>(..).inline_values();
+        assert_eq!(entries.count(), 10_000);
+        file.tree().check_fences();
+        drop(file);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_tree_reads() {
+        let path = data_file("file_tree_reads");
+        let cache = 64;
+
+        let mut file = FileTree::<4096>::create(&path).unwrap();
+
+        for i in 0..10_000 {
+            let tree = file.tree_mut();
+            tree.insert_value(&file_key(i), format!("{i}").as_bytes())
+                .unwrap();
+        }
+
+        file.flush().unwrap();
+        assert!(file.pages() > 10 * cache as u64);
+        drop(file);
+
+        // only what `get` and `range` are on is pinned, everything else makes room for it
+        let mut file = FileTree::<4096>::open(&path).unwrap();
+        file.set_cache_size(cache);
+
+        for i in (0..10_000).step_by(7) {
+            let value = file.get(&file_key(i)).unwrap();
+            assert_eq!(value, Some(format!("{i}").into_bytes()));
+            assert!(file.cached_pages() <= cache);
+        }
+
+        let mut expected: Vec<_> = (0..10_000).map(file_key).collect();
+        expected.sort();
+
+        let mut keys = expected.iter();
+
+        for entry in file.range::<&[u8], RangeFull>(..) {
+            assert_eq!(entry.unwrap().0, keys.next().unwrap().as_bytes());
+            assert!(file.cached_pages() <= cache);
+        }
+
+        assert!(keys.next().is_none());
+
+        let start = file_key(5_000);
+        let tail = expected.iter().filter(|key| **key >= start).rev();
+
+        for (entry, key) in file.range(start.as_bytes()..).rev().zip(tail) {
+            assert_eq!(entry.unwrap().0, key.as_bytes());
+            assert!(file.cached_pages() <= cache);
+        }
+
+        drop(file);
+
+        // a node that is gone from the file is reported instead of panicking
+        let data = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        data.set_len(4096).unwrap();
+        drop(data);
+
+        let file = FileTree::<4096>::open(&path).unwrap();
+        let err = || BTreeError::PageRead(std::io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(file.get(&file_key(1)), Err(err()));
+        assert_eq!(
+            file.range::<&[u8], RangeFull>(..).collect::<Vec<_>>(),
+            [Err(err())]
+        );
+        drop(file);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod btree_bench {
+    extern crate test;
+
+    use test::{black_box, Bencher};
+
+    use crate::{btree::BTree, flex::ptr_to_bytes, slotted_leaf::SlottedLeaf};
+
+    // a page full of short keys, about 200 of them
+    fn full_leaf(key: impl Fn(u64) -> Vec<u8>) -> (SlottedLeaf<()>, Vec<Vec<u8>>) {
+        let mut leaf = SlottedLeaf::new();
+        let mut keys = vec![];
+
+        for i in 0.. {
+            let key = key(i * 7919 % 100_000);
+
+            if !leaf.can_fit(&key, &ptr_to_bytes(std::ptr::null_mut())) {
+                break;
+            }
+
+            leaf.insert(&key, std::ptr::null_mut()).unwrap();
+            keys.push(key);
+        }
+
+        (leaf, keys)
+    }
+
+    // the first four bytes tell all keys apart, so the hints decide everything
+    fn distinct_hints(i: u64) -> Vec<u8> {
+        i.wrapping_mul(0x9E37_79B9).to_be_bytes()[4..].to_vec()
+    }
+
+    // every key shares its first four bytes, so each comparison has to go to the heap
+    fn shared_prefix(i: u64) -> Vec<u8> {
+        format!("user{i:08}").into_bytes()
+    }
+
+    fn bench_leaf(b: &mut Bencher, key: impl Fn(u64) -> Vec<u8>) {
+        let (leaf, keys) = full_leaf(key);
+
+        b.iter(|| {
+            for key in &keys {
+                black_box(leaf.get_upper_bound(black_box(key)));
+            }
+        });
+    }
+
+    // always the binary search over the slot array, whatever layout `get_upper_bound` uses
+    fn bench_leaf_scalar(b: &mut Bencher, key: impl Fn(u64) -> Vec<u8>) {
+        let (leaf, keys) = full_leaf(key);
+
+        b.iter(|| {
+            for key in &keys {
+                black_box(leaf.get_upper_bound_scalar(black_box(key)));
+            }
+        });
+    }
+
+    #[bench]
+    fn leaf_search(b: &mut Bencher) {
+        bench_leaf(b, distinct_hints);
+    }
+
+    #[bench]
+    fn leaf_search_scalar(b: &mut Bencher) {
+        bench_leaf_scalar(b, distinct_hints);
+    }
+
+    #[bench]
+    fn leaf_search_shared_prefix(b: &mut Bencher) {
+        bench_leaf(b, shared_prefix);
+    }
+
+    #[bench]
+    fn leaf_search_shared_prefix_scalar(b: &mut Bencher) {
+        bench_leaf_scalar(b, shared_prefix);
+    }
+
+    #[bench]
+    fn tree_get(b: &mut Bencher) {
+        let mut tree: BTree<()> = BTree::new();
+        let keys: Vec<_> = (0..100_000u64)
+            .map(|i| format!("{:016}", i.wrapping_mul(0x9E37_79B9_7F4A_7C15) % 1_000_000))
+            .collect();
+
+        for key in &keys {
+            tree.insert(key, std::ptr::null_mut()).unwrap();
+        }
+
+        b.iter(|| {
+            for key in keys.iter().step_by(97) {
+                black_box(tree.get(black_box(key)).unwrap());
+            }
+        });
+    }
+
+    #[bench]
+    fn tree_insert(b: &mut Bencher) {
+        b.iter(|| {
+            let mut tree: BTree<()> = BTree::new();
+
+            for i in 0..10_000u64 {
+                let key = (i.wrapping_mul(0x9E37_79B9_7F4A_7C15) % 1_000_000).to_be_bytes();
+                tree.insert(&key, std::ptr::null_mut()).unwrap();
+            }
+
+            tree
+        });
     }
 }
@@ -0,0 +1,164 @@
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    btree::{BTree, BTreeError, InsertResult},
+    encoding::KeyEncode,
+    iter,
+};
+
+/// An ordered map over a [`BTree`] that owns its values. Keys are stored in their
+/// [`KeyEncode`] form and decoded again when iterating. Values are boxed on insert and freed when
+/// they are removed, overwritten or the map is dropped.
+pub struct BTreeMap<K: KeyEncode, V: Debug> {
+    tree: BTree<V>,
+    len: usize,
+    boo: PhantomData<K>,
+}
+
+impl<K: KeyEncode, V: Debug> BTreeMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            tree: BTree::new(),
+            len: 0,
+            boo: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaced.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, BTreeError> {
+        let value = Box::into_raw(Box::new(value));
+
+        let res = match self.tree.insert(&key.to_key(), value) {
+            Ok(res) => res,
+            Err(err) => {
+                // the tree did not take it, so it is still ours to free
+                let _boxed = unsafe { Box::from_raw(value) };
+                return Err(err);
+            }
+        };
+
+        Ok(match res {
+            InsertResult::Inserted => {
+                self.len += 1;
+                None
+            }
+            InsertResult::Replaced(old) => Some(*unsafe { Box::from_raw(old as *mut V) }),
+            InsertResult::Present(_) => unreachable!("insert always replaces"),
+        })
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<&V>, BTreeError> {
+        Ok(self
+            .tree
+            .get(&key.to_key())?
+            .map(|value| unsafe { &*value }))
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Result<Option<&mut V>, BTreeError> {
+        Ok(self
+            .tree
+            .get(&key.to_key())?
+            .map(|value| unsafe { &mut *value }))
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool, BTreeError> {
+        Ok(self.tree.get(&key.to_key())?.is_some())
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.tree.remove(&key.to_key())?;
+        self.len -= 1;
+        Some(*unsafe { Box::from_raw(value) })
+    }
+
+    /// Iterates over the entries with keys inside `range`. The bounds are encoded first, which
+    /// keeps their order, so this is a single range scan over the tree.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Iter<'_, K, V> {
+        let start = range.start_bound().map(KeyEncode::to_key);
+        let end = range.end_bound().map(KeyEncode::to_key);
+
+        Iter {
+            inner: self
+                .tree
+                .range::<Vec<u8>, (Bound<_>, Bound<_>)>((start, end)),
+            boo: PhantomData,
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.range(..)
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = K> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> + '_ {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+impl<K: KeyEncode, V: Debug> Default for BTreeMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: KeyEncode, V: Debug> Drop for BTreeMap<K, V> {
+    fn drop(&mut self) {
+        // the tree only frees its pages, the values behind them are ours
+        for value in self.tree.values() {
+            let _boxed = unsafe { Box::from_raw(value) };
+        }
+    }
+}
+
+impl<'a, K: KeyEncode, V: Debug> IntoIterator for &'a BTreeMap<K, V> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Entries of a [`BTreeMap`] with their keys decoded. Created by [`BTreeMap::iter`] and
+/// [`BTreeMap::range`].
+pub struct Iter<'a, K: KeyEncode, V: Debug> {
+    inner: iter::Range<'a, V>,
+    boo: PhantomData<K>,
+}
+
+impl<'a, K: KeyEncode, V: Debug> Iter<'a, K, V> {
+    fn decode((key, value): (Cow<'a, [u8]>, *mut V)) -> (K, &'a V) {
+        let key = K::from_key(&key).expect("Keys are only written by the map");
+        (key, unsafe { &*value })
+    }
+}
+
+impl<'a, K: KeyEncode, V: Debug> Iterator for Iter<'a, K, V> {
+    type Item = (K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(Self::decode)
+    }
+}
+
+impl<K: KeyEncode, V: Debug> DoubleEndedIterator for Iter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(Self::decode)
+    }
+}
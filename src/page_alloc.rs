@@ -0,0 +1,403 @@
+use std::{
+    alloc::{self, Layout},
+    cell::{Cell, RefCell},
+    fmt::{self, Debug},
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    btree::{BTreeError, Node},
+    PAGE_SIZE,
+};
+
+/// Hands out the memory for the pages of a tree. Every request is for a single leaf or branch, so
+/// an allocator may assume `layout.size()` is the page size of the tree it serves.
+///
+/// # Safety
+///
+/// `allocate` has to return memory that fits `layout` and stays valid until it is passed back to
+/// `deallocate` with the same layout. `page` has to return the node behind a reference from
+/// `alloc_node`, at an address that stays valid until the node is freed or `evict` is called.
+/// Pinned nodes stay where they are until they are unpinned, whatever else happens.
+pub unsafe trait PageAllocator {
+    /// Whether all pages are released together once the allocator goes away. Trees skip handing
+    /// back their pages one by one on drop then, overflow pages included
+    const BULK_FREE: bool = false;
+
+    /// A fresh page for `layout`, or `None` if there is no memory left to give out
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// # Safety
+    ///
+    /// `page` has to come from `allocate` on this allocator with the same `layout`, and must not be
+    /// used afterwards.
+    unsafe fn deallocate(&self, page: NonNull<u8>, layout: Layout);
+
+    /// How many more pages `allocate` is sure to hand out, or `None` if only the system sets a
+    /// limit. Trees check this before an insert, so they never run out halfway through a split
+    fn available_pages(&self) -> Option<usize> {
+        None
+    }
+
+    /// Moves `node` into a page of its own. If there is none left, `node` is dropped
+    fn alloc_node<N>(&self, node: N) -> Result<*mut N, BTreeError> {
+        let page = self
+            .allocate(Layout::new::<N>())
+            .ok_or(BTreeError::OutOfPages)?;
+
+        let page = page.cast::<N>().as_ptr();
+        unsafe { page.write(node) };
+        Ok(page)
+    }
+
+    /// Drops the node behind `node` and hands its page back.
+    ///
+    /// # Safety
+    ///
+    /// `node` has to come from `alloc_node` on this allocator and must not be used afterwards.
+    unsafe fn free_node<N>(&self, node: *mut N) {
+        unsafe {
+            ptr::drop_in_place(node);
+            self.deallocate(NonNull::new_unchecked(node).cast(), Layout::new::<N>());
+        }
+    }
+
+    /// The address of the node `node` refers to. Nodes refer to each other by what `alloc_node`
+    /// handed out for them, which is their address unless the allocator keeps its pages somewhere
+    /// else, like a buffer pool over a data file. Such an allocator reads the page in if it has
+    /// to. The address stays valid until the next call to `evict`
+    fn page(&self, node: Node) -> Node {
+        node
+    }
+
+    /// Like `page`, but a page that can't be read back is reported instead of panicking
+    fn try_page(&self, node: Node) -> Result<Node, BTreeError> {
+        Ok(self.page(node))
+    }
+
+    /// Keeps the node at `page` where it is until it is unpinned as many times as it was pinned.
+    /// Iterators pin the leaves they are on, so the allocator is free to let go of the others
+    fn pin(&self, _page: Node) {}
+
+    fn unpin(&self, _page: Node) {}
+
+    /// What other nodes refer to the node at `page` by, the other way round from `page`
+    fn reference(&self, page: Node) -> Node {
+        page
+    }
+
+    /// The node at `page` changed. Allocators that write their pages back somewhere have to do so
+    /// before they let go of it, the others can ignore this
+    fn mark_dirty(&self, _page: Node) {}
+
+    /// Called by trees right before a change, while nothing borrows from their pages. An allocator
+    /// that reads pages in may drop some of them here
+    fn evict(&self) {}
+}
+
+unsafe impl<A: PageAllocator> PageAllocator for &A {
+    const BULK_FREE: bool = A::BULK_FREE;
+
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        (**self).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, page: NonNull<u8>, layout: Layout) {
+        unsafe { (**self).deallocate(page, layout) }
+    }
+
+    fn available_pages(&self) -> Option<usize> {
+        (**self).available_pages()
+    }
+
+    fn alloc_node<N>(&self, node: N) -> Result<*mut N, BTreeError> {
+        (**self).alloc_node(node)
+    }
+
+    unsafe fn free_node<N>(&self, node: *mut N) {
+        unsafe { (**self).free_node(node) }
+    }
+
+    fn page(&self, node: Node) -> Node {
+        (**self).page(node)
+    }
+
+    fn try_page(&self, node: Node) -> Result<Node, BTreeError> {
+        (**self).try_page(node)
+    }
+
+    fn pin(&self, page: Node) {
+        (**self).pin(page)
+    }
+
+    fn unpin(&self, page: Node) {
+        (**self).unpin(page)
+    }
+
+    fn reference(&self, page: Node) -> Node {
+        (**self).reference(page)
+    }
+
+    fn mark_dirty(&self, page: Node) {
+        (**self).mark_dirty(page)
+    }
+
+    fn evict(&self) {
+        (**self).evict()
+    }
+}
+
+/// Takes every page straight from the global allocator and gives it back on its own, just like
+/// boxing the nodes would. This is what trees use unless they are given something else.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Heap;
+
+unsafe impl PageAllocator for Heap {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { alloc::alloc(layout) })
+    }
+
+    unsafe fn deallocate(&self, page: NonNull<u8>, layout: Layout) {
+        unsafe { alloc::dealloc(page.as_ptr(), layout) }
+    }
+}
+
+// the pool reserves its pages in chunks of this many bytes, aligned to their size. That is a huge
+// page on x86_64, so the kernel can back a whole chunk with one if transparent huge pages are on
+const CHUNK_LEN: usize = 2 << 20;
+
+/// Reserves `P` byte pages in large aligned chunks and keeps pages that are freed for the next
+/// allocation instead of returning them. Every page is aligned to `P`, which has to be a power of
+/// two. Memory only goes back to the system when the pool is dropped, so the pool has to outlive
+/// the trees using it. Share one between trees by handing them `&PagePool`.
+pub struct PagePool<const P: usize = PAGE_SIZE> {
+    chunks: RefCell<Vec<(NonNull<u8>, Layout)>>,
+    free: RefCell<Vec<NonNull<u8>>>,
+    // pages in all chunks, free or not
+    reserved: Cell<usize>,
+    limit: Option<usize>,
+}
+
+impl<const P: usize> PagePool<P> {
+    pub fn new() -> Self {
+        Self {
+            chunks: RefCell::new(vec![]),
+            free: RefCell::new(vec![]),
+            reserved: Cell::new(0),
+            limit: None,
+        }
+    }
+
+    /// A pool that never holds more than `pages` pages. Allocating past that fails
+    pub fn with_limit(pages: usize) -> Self {
+        let mut pool = Self::new();
+        pool.limit = Some(pages);
+        pool
+    }
+
+    /// Pages reserved from the system so far, whether they are in use or not
+    pub fn reserved_pages(&self) -> usize {
+        self.reserved.get()
+    }
+
+    /// Pages waiting to be handed out again
+    pub fn free_pages(&self) -> usize {
+        self.free.borrow().len()
+    }
+
+    /// Pages currently handed out
+    pub fn used_pages(&self) -> usize {
+        self.reserved_pages() - self.free_pages()
+    }
+
+    // reserves the next chunk and puts its pages on the free list. A chunk gets cut short by the
+    // limit, in which case it is only aligned to the page size
+    fn grow(&self) -> Option<()> {
+        let pages = (CHUNK_LEN / P).max(1);
+        let pages = match self.limit {
+            Some(limit) => pages.min(limit.checked_sub(self.reserved.get())?),
+            None => pages,
+        };
+
+        if pages == 0 {
+            return None;
+        }
+
+        let len = pages * P;
+        let align = if len == CHUNK_LEN { CHUNK_LEN } else { P };
+        let layout = Layout::from_size_align(len, align).ok()?;
+        let chunk = NonNull::new(unsafe { alloc::alloc(layout) })?;
+
+        self.chunks.borrow_mut().push((chunk, layout));
+        self.reserved.set(self.reserved.get() + pages);
+
+        // handed out from the front of the chunk first, that's the end of the free list
+        self.free
+            .borrow_mut()
+            .extend((0..pages).rev().map(|page| unsafe { chunk.add(page * P) }));
+
+        Some(())
+    }
+}
+
+impl<const P: usize> Default for PagePool<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const P: usize> PageAllocator for PagePool<P> {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        assert!(
+            layout.size() <= P && layout.align() <= P,
+            "Node does not fit into a page of the pool"
+        );
+
+        if self.free.borrow().is_empty() {
+            self.grow()?;
+        }
+
+        self.free.borrow_mut().pop()
+    }
+
+    unsafe fn deallocate(&self, page: NonNull<u8>, _layout: Layout) {
+        self.free.borrow_mut().push(page);
+    }
+
+    fn available_pages(&self) -> Option<usize> {
+        let limit = self.limit?;
+        Some(limit - self.reserved.get() + self.free_pages())
+    }
+}
+
+impl<const P: usize> Debug for PagePool<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PagePool")
+            .field("page_size", &P)
+            .field("reserved_pages", &self.reserved_pages())
+            .field("free_pages", &self.free_pages())
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+impl<const P: usize> Drop for PagePool<P> {
+    fn drop(&mut self) {
+        for (chunk, layout) in self.chunks.get_mut().drain(..) {
+            unsafe { alloc::dealloc(chunk.as_ptr(), layout) };
+        }
+    }
+}
+
+/// Hands out `P` byte pages from a single region reserved up front, which goes back to the system
+/// in one piece when the arena is dropped. Trees on an arena don't walk their pages when they are
+/// dropped, which makes throwaway indexes cheap to tear down. Freed pages are still handed out
+/// again. Like `PagePool`, `P` has to be a power of two.
+pub struct Arena<const P: usize = PAGE_SIZE> {
+    region: NonNull<u8>,
+    layout: Layout,
+    // pages handed out of the region so far, including the ones on the free list
+    next: Cell<usize>,
+    free: RefCell<Vec<NonNull<u8>>>,
+}
+
+impl<const P: usize> Arena<P> {
+    /// An arena for at most `pages` pages. Memory the tree never touches is usually not backed by
+    /// the system, so this can be generous. Allocating past it fails
+    pub fn new(pages: usize) -> Self {
+        let layout = Layout::from_size_align(pages.max(1) * P, P).expect("Arena too large");
+        let Some(region) = NonNull::new(unsafe { alloc::alloc(layout) }) else {
+            alloc::handle_alloc_error(layout);
+        };
+
+        Self {
+            region,
+            layout,
+            next: Cell::new(0),
+            free: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.layout.size() / P
+    }
+
+    /// Pages currently handed out
+    pub fn used_pages(&self) -> usize {
+        self.next.get() - self.free.borrow().len()
+    }
+}
+
+unsafe impl<const P: usize> PageAllocator for Arena<P> {
+    const BULK_FREE: bool = true;
+
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        assert!(
+            layout.size() <= P && layout.align() <= P,
+            "Node does not fit into a page of the arena"
+        );
+
+        if let Some(page) = self.free.borrow_mut().pop() {
+            return Some(page);
+        }
+
+        let next = self.next.get();
+
+        if next == self.capacity() {
+            return None;
+        }
+
+        self.next.set(next + 1);
+        Some(unsafe { self.region.add(next * P) })
+    }
+
+    unsafe fn deallocate(&self, page: NonNull<u8>, _layout: Layout) {
+        self.free.borrow_mut().push(page);
+    }
+
+    fn available_pages(&self) -> Option<usize> {
+        Some(self.capacity() - self.used_pages())
+    }
+}
+
+impl<const P: usize> Debug for Arena<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Arena")
+            .field("page_size", &P)
+            .field("capacity", &self.capacity())
+            .field("used_pages", &self.used_pages())
+            .finish()
+    }
+}
+
+impl<const P: usize> Drop for Arena<P> {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.region.as_ptr(), self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod page_alloc_tests {
+    use std::alloc::Layout;
+
+    use super::{PageAllocator, PagePool};
+
+    #[test]
+    fn limit() {
+        let pool = PagePool::<4096>::with_limit(3);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+
+        let pages: Vec<_> = (0..3).map(|_| pool.allocate(layout).unwrap()).collect();
+        assert!(pool.allocate(layout).is_none());
+        assert_eq!(pool.available_pages(), Some(0));
+
+        for page in &pages {
+            assert_eq!(page.as_ptr() as usize % 4096, 0);
+        }
+
+        unsafe { pool.deallocate(pages[1], layout) };
+        assert_eq!(pool.available_pages(), Some(1));
+        assert_eq!(pool.allocate(layout), Some(pages[1]));
+        assert_eq!(pool.reserved_pages(), 3);
+    }
+}
@@ -0,0 +1,831 @@
+use std::{
+    alloc::{self, Layout},
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt::{self, Debug},
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::RangeBounds,
+    path::Path,
+    ptr::{self, NonNull},
+    slice,
+};
+
+use crate::{
+    btree::{BTree, BTreeError, Node},
+    flex::{Flex, HEAD_LEN},
+    iter::InlineValues,
+    page_alloc::PageAllocator,
+    slotted_leaf::SlottedLeaf,
+    PAGE_SIZE,
+};
+
+// nodes refer to each other by page id, in memory as well as in the file, so ids have to fit into
+// a pointer
+const _: () = assert!(
+    size_of::<usize>() == size_of::<u64>(),
+    "Data files need 64-bit pointers"
+);
+
+const MAGIC: [u8; 8] = *b"BTREEPG1";
+
+// page 0 of a data file is its header, so no link ever points there. It stands for a missing one
+const NO_PAGE: u64 = 0;
+
+// behind the magic, the header page holds these as native endian words: page size, root, height,
+// pages in the file, head of the free list and split window
+const HEADER_WORDS: usize = 6;
+
+// pages a buffer pool keeps in memory unless it is told otherwise
+const DEFAULT_CACHE_PAGES: usize = 1024;
+
+/// A data file of `P` byte pages, addressed by their number. Pages that are no longer used are
+/// chained together through their first word and handed out again before the file grows
+struct PageFile<const P: usize> {
+    file: File,
+    // pages in the file, including the header and the free ones
+    pages: u64,
+    // the chain of free pages, its head last
+    free: Vec<u64>,
+    // how many of the free pages, from the end of the chain, are linked up in the file already
+    linked: usize,
+}
+
+impl<const P: usize> PageFile<P> {
+    fn read(&mut self, id: u64, bytes: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(id * P as u64))?;
+        self.file.read_exact(bytes)
+    }
+
+    fn write(&mut self, id: u64, bytes: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(id * P as u64))?;
+        self.file.write_all(bytes)
+    }
+
+    /// A page to write to, from the free list if there is one
+    fn allocate(&mut self) -> u64 {
+        if let Some(id) = self.free.pop() {
+            self.linked = self.linked.min(self.free.len());
+            return id;
+        }
+
+        let id = self.pages;
+        self.pages += 1;
+        id
+    }
+
+    /// Puts `id` on the free list. Its link only goes into the file with `link_free`
+    fn free(&mut self, id: u64) {
+        self.free.push(id);
+    }
+
+    // writes the links of the pages freed since the last time
+    fn link_free(&mut self) -> io::Result<()> {
+        for index in self.linked..self.free.len() {
+            let next = index.checked_sub(1).map_or(NO_PAGE, |next| self.free[next]);
+            let id = self.free[index];
+
+            self.write(id, &next.to_ne_bytes())?;
+            self.linked = index + 1;
+        }
+
+        Ok(())
+    }
+
+    // ids come from the file, so they are checked before they are followed
+    fn check(&self, id: u64) -> io::Result<()> {
+        match id != NO_PAGE && id < self.pages {
+            true => Ok(()),
+            false => Err(invalid(format!("page {id} is not in the file"))),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_word(bytes: &[u8], at: usize) -> u64 {
+    u64::from_ne_bytes(bytes[at..at + size_of::<u64>()].try_into().unwrap())
+}
+
+fn write_word(bytes: &mut [u8], at: usize, word: u64) {
+    bytes[at..at + size_of::<u64>()].copy_from_slice(&word.to_ne_bytes());
+}
+
+fn bytes<'a, const P: usize>(page: NonNull<u8>) -> &'a mut [u8] {
+    unsafe { slice::from_raw_parts_mut(page.as_ptr(), P) }
+}
+
+// leaves keep their back link in bytes reserved at the end of the page, branches reserve none.
+// That tells the two apart without knowing where in the tree a page is
+fn is_leaf<const P: usize>(page: &Flex<P>) -> bool {
+    page.reserved().len() == P - SlottedLeaf::<(), P>::PREV_AT
+}
+
+// the keys the node in `page` keeps in overflow pages: where the link to the first one is, and
+// how long the key is
+fn stubs<const P: usize>(page: NonNull<u8>) -> Vec<(usize, usize)> {
+    let page = unsafe { &*(page.as_ptr() as *const Flex<P>) };
+
+    page.spilled_keys(is_leaf(page))
+        .into_iter()
+        .map(|(at, len)| (HEAD_LEN + at, len))
+        .collect()
+}
+
+fn allocate_page(layout: Layout) -> NonNull<u8> {
+    NonNull::new(unsafe { alloc::alloc(layout) })
+        .unwrap_or_else(|| alloc::handle_alloc_error(layout))
+}
+
+// a node in memory, see `BufferPool`
+struct Frame {
+    page: NonNull<u8>,
+    // changed since it was last read or written
+    dirty: bool,
+    // used since the clock last came by
+    used: bool,
+    // iterators on the node, which keep it from being let go of
+    pins: usize,
+}
+
+struct Frames<const P: usize> {
+    file: PageFile<P>,
+    frames: HashMap<u64, Frame>,
+    // the id of every frame, by address
+    ids: HashMap<usize, u64>,
+    // overflow pages in memory that have a page in the file, by address. Chains are only made or
+    // released as a whole, so these never have to be written again
+    stored: HashMap<usize, u64>,
+    // overflow pages in memory, stored or not
+    overflow_pages: usize,
+    // ids in the order the clock passes them. Freed ones are skipped once it gets to them
+    clock: VecDeque<u64>,
+    capacity: usize,
+    // set while a read on the `FileTree` is under way. Nothing outside the pool borrows from
+    // nodes that are not pinned then, so reading in one may let go of others
+    reading: bool,
+}
+
+impl<const P: usize> Frames<P> {
+    fn node_layout() -> Layout {
+        Layout::new::<SlottedLeaf<(), P>>()
+    }
+
+    fn cached_pages(&self) -> usize {
+        self.frames.len() + self.overflow_pages
+    }
+
+    // reads the node `id` into a frame of its own, along with its overflow chains. Their links in
+    // memory are addresses
+    fn load(&mut self, id: u64) -> io::Result<NonNull<u8>> {
+        self.file.check(id)?;
+
+        let page = allocate_page(Self::node_layout());
+        let mut chains = vec![];
+
+        let loaded = self.file.read(id, bytes::<P>(page)).and_then(|()| {
+            for (at, len) in stubs::<P>(page) {
+                let first = self.load_chain(read_word(bytes::<P>(page), at), len)?;
+                write_word(bytes::<P>(page), at, first as u64);
+                chains.push(first);
+            }
+
+            Ok(())
+        });
+
+        if let Err(err) = loaded {
+            chains
+                .into_iter()
+                .for_each(|first| self.unload_chain(first));
+            unsafe { alloc::dealloc(page.as_ptr(), Self::node_layout()) };
+            return Err(err);
+        }
+
+        self.add_frame(id, page, false);
+        Ok(page)
+    }
+
+    fn add_frame(&mut self, id: u64, page: NonNull<u8>, dirty: bool) {
+        self.frames.insert(
+            id,
+            Frame {
+                page,
+                dirty,
+                used: true,
+                pins: 0,
+            },
+        );
+        self.ids.insert(page.as_ptr() as usize, id);
+        self.clock.push_back(id);
+    }
+
+    // reads the chain of a `len` byte key starting at page `first` and returns the address of
+    // its first page in memory
+    fn load_chain(&mut self, first: u64, len: usize) -> io::Result<usize> {
+        let layout = Flex::<P>::overflow_layout();
+        let mut pages: Vec<(NonNull<u8>, u64)> = vec![];
+        let mut id = first;
+
+        for _ in 0..Flex::<P>::overflow_pages(len) {
+            let page = allocate_page(layout);
+            pages.push((page, id));
+
+            let read = self.file.check(id);
+            if let Err(err) = read.and_then(|()| self.file.read(id, bytes::<P>(page))) {
+                for (page, _) in pages {
+                    unsafe { alloc::dealloc(page.as_ptr(), layout) };
+                }
+
+                return Err(err);
+            }
+
+            id = Flex::<P>::next_overflow(bytes::<P>(page)) as u64;
+        }
+
+        let mut next = 0;
+
+        for &(page, id) in pages.iter().rev() {
+            Flex::<P>::set_next_overflow(bytes::<P>(page), next);
+            next = page.as_ptr() as usize;
+            self.stored.insert(next, id);
+        }
+
+        self.overflow_pages += pages.len();
+        Ok(next)
+    }
+
+    // lets go of the chain at `first` in memory. It stays in the file
+    fn unload_chain(&mut self, first: usize) {
+        let mut next = first;
+
+        while let Some(page) = NonNull::new(next as *mut u8) {
+            next = Flex::<P>::next_overflow(bytes::<P>(page));
+
+            self.stored.remove(&(page.as_ptr() as usize));
+            self.overflow_pages -= 1;
+            unsafe { alloc::dealloc(page.as_ptr(), Flex::<P>::overflow_layout()) };
+        }
+    }
+
+    // lets go of the frame `id` and its overflow chains in memory
+    fn unload(&mut self, id: u64) {
+        let frame = self.frames.remove(&id).unwrap();
+        self.ids.remove(&(frame.page.as_ptr() as usize));
+
+        for (at, _) in stubs::<P>(frame.page) {
+            self.unload_chain(read_word(bytes::<P>(frame.page), at) as usize);
+        }
+
+        unsafe { alloc::dealloc(frame.page.as_ptr(), Self::node_layout()) };
+    }
+
+    // writes the frame `id` with its links to overflow chains as ids. Chains that are not in the
+    // file yet are written first
+    fn write_back(&mut self, id: u64) -> io::Result<()> {
+        let page = self.frames[&id].page;
+        let mut image = bytes::<P>(page).to_vec();
+
+        for (at, _) in stubs::<P>(page) {
+            let first = self.store_chain(read_word(&image, at) as usize)?;
+            write_word(&mut image, at, first);
+        }
+
+        self.file.write(id, &image)?;
+        self.frames.get_mut(&id).unwrap().dirty = false;
+
+        Ok(())
+    }
+
+    // the id of the first page of the chain at `first`, once it is in the file
+    fn store_chain(&mut self, first: usize) -> io::Result<u64> {
+        if let Some(&id) = self.stored.get(&first) {
+            return Ok(id);
+        }
+
+        let mut pages = vec![];
+        let mut next = first;
+
+        while let Some(page) = NonNull::new(next as *mut u8) {
+            pages.push(page);
+            next = Flex::<P>::next_overflow(bytes::<P>(page));
+        }
+
+        let ids: Vec<_> = pages.iter().map(|_| self.file.allocate()).collect();
+
+        for (index, &page) in pages.iter().enumerate() {
+            let mut image = bytes::<P>(page).to_vec();
+            let next = ids.get(index + 1).copied().unwrap_or(NO_PAGE);
+            Flex::<P>::set_next_overflow(&mut image, next as usize);
+
+            if let Err(err) = self.file.write(ids[index], &image) {
+                ids.iter().for_each(|&id| self.file.free(id));
+                return Err(err);
+            }
+        }
+
+        for (page, &id) in pages.iter().zip(&ids) {
+            self.stored.insert(page.as_ptr() as usize, id);
+        }
+
+        Ok(ids[0])
+    }
+
+    fn evict(&mut self) {
+        self.trim(self.capacity);
+    }
+
+    // lets go of frames the clock finds unused and unpinned until no more than `pages` are left.
+    // Dirty ones are written first. If that fails they stay, the next flush reports it
+    fn trim(&mut self, pages: usize) {
+        // every frame is passed at most twice, once to clear its flag and once to go
+        let mut steps = 2 * self.clock.len();
+
+        while self.cached_pages() > pages && steps > 0 {
+            steps -= 1;
+
+            let Some(id) = self.clock.pop_front() else {
+                break;
+            };
+
+            let Some(frame) = self.frames.get_mut(&id) else {
+                continue;
+            };
+
+            if frame.used || frame.pins > 0 {
+                frame.used = false;
+                self.clock.push_back(id);
+                continue;
+            }
+
+            if frame.dirty && self.write_back(id).is_err() {
+                self.clock.push_back(id);
+                return;
+            }
+
+            self.unload(id);
+        }
+    }
+
+    // writes every dirty frame and links up the free pages. Returns how many nodes were written
+    fn write_dirty(&mut self) -> io::Result<usize> {
+        let mut dirty: Vec<_> = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(&id, _)| id)
+            .collect();
+        dirty.sort_unstable();
+
+        for &id in &dirty {
+            self.write_back(id)?;
+        }
+
+        self.file.link_free()?;
+        Ok(dirty.len())
+    }
+}
+
+/// Keeps the nodes of a [`FileTree`] in memory while they are used. Nodes refer to each other by
+/// their page id in the data file, and a node is only read in the first time the tree asks for
+/// it. Once more than the capacity is in memory, changes to the tree and flushes let go of nodes
+/// that were not used for a while, using the clock algorithm. So do the reads on the `FileTree`
+/// itself, which leave the nodes their iterators are on pinned. Nodes that changed are written back
+/// first. Keys too long for their node live in chains of overflow pages, which come and go along
+/// with it.
+///
+/// A page that can't be read back is reported as [`BTreeError::PageRead`] by lookups, inserts and
+/// iterators. Anywhere else the tree has no way of reporting it, so it panics.
+pub struct BufferPool<const P: usize = PAGE_SIZE> {
+    frames: RefCell<Frames<P>>,
+}
+
+impl<const P: usize> BufferPool<P> {
+    fn new(file: PageFile<P>) -> Self {
+        Self {
+            frames: RefCell::new(Frames {
+                file,
+                frames: HashMap::new(),
+                ids: HashMap::new(),
+                stored: HashMap::new(),
+                overflow_pages: 0,
+                clock: VecDeque::new(),
+                capacity: DEFAULT_CACHE_PAGES,
+                reading: false,
+            }),
+        }
+    }
+
+    /// Pages kept in memory before nodes are let go of. The tree may need a few more while a
+    /// change is under way
+    pub fn capacity(&self) -> usize {
+        self.frames.borrow().capacity
+    }
+
+    /// Nodes and overflow pages currently in memory
+    pub fn cached_pages(&self) -> usize {
+        self.frames.borrow().cached_pages()
+    }
+
+    // runs `read` with `page` free to let go of nodes that are not pinned. Nothing `read` returns
+    // may borrow from the pool
+    fn reading<R>(&self, read: impl FnOnce() -> R) -> R {
+        self.frames.borrow_mut().reading = true;
+        let res = read();
+        self.frames.borrow_mut().reading = false;
+
+        res
+    }
+
+    // writes everything that changed, then the header, and waits for the file to be synced
+    fn flush(&self, root: Node, height: usize, split_window: usize) -> io::Result<usize> {
+        let mut frames = self.frames.borrow_mut();
+        let written = frames.write_dirty()?;
+
+        let words = [
+            P as u64,
+            root as u64,
+            height as u64,
+            frames.file.pages,
+            frames.file.free.last().copied().unwrap_or(NO_PAGE),
+            split_window as u64,
+        ];
+
+        let mut header = MAGIC.to_vec();
+        header.extend(words.iter().flat_map(|word| word.to_ne_bytes()));
+
+        frames.file.write(0, &header)?;
+        frames.file.file.sync_data()?;
+
+        Ok(written)
+    }
+}
+
+unsafe impl<const P: usize> PageAllocator for BufferPool<P> {
+    // the pages stay in the file, only their frames go away with the pool
+    const BULK_FREE: bool = true;
+
+    // only overflow pages are allocated like this, nodes go through `alloc_node`
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        debug_assert_eq!(layout, Flex::<P>::overflow_layout());
+
+        self.frames.borrow_mut().overflow_pages += 1;
+        Some(allocate_page(layout))
+    }
+
+    unsafe fn deallocate(&self, page: NonNull<u8>, layout: Layout) {
+        let mut frames = self.frames.borrow_mut();
+
+        if let Some(id) = frames.stored.remove(&(page.as_ptr() as usize)) {
+            frames.file.free(id);
+        }
+
+        frames.overflow_pages -= 1;
+        unsafe { alloc::dealloc(page.as_ptr(), layout) };
+    }
+
+    /// Gives the node a page in the file and a dirty frame. The node is referred to by the id of
+    /// that page
+    fn alloc_node<N>(&self, node: N) -> Result<*mut N, BTreeError> {
+        assert_eq!(
+            Layout::new::<N>(),
+            Frames::<P>::node_layout(),
+            "Node does not fit into a page of the pool"
+        );
+
+        let mut frames = self.frames.borrow_mut();
+        let id = frames.file.allocate();
+        let page = allocate_page(Frames::<P>::node_layout());
+
+        unsafe { page.cast::<N>().write(node) };
+        frames.add_frame(id, page, true);
+
+        Ok(id as *mut N)
+    }
+
+    unsafe fn free_node<N>(&self, node: *mut N) {
+        let mut frames = self.frames.borrow_mut();
+        let id = node as u64;
+
+        if let Some(frame) = frames.frames.remove(&id) {
+            frames.ids.remove(&(frame.page.as_ptr() as usize));
+
+            unsafe {
+                ptr::drop_in_place(frame.page.cast::<N>().as_ptr());
+                alloc::dealloc(frame.page.as_ptr(), Frames::<P>::node_layout());
+            }
+        }
+
+        frames.file.free(id);
+    }
+
+    fn page(&self, node: Node) -> Node {
+        match self.try_page(node) {
+            Ok(page) => page,
+            Err(err) => panic!(
+                "Could not read page {} of the data file: {err}",
+                node as u64
+            ),
+        }
+    }
+
+    fn try_page(&self, node: Node) -> Result<Node, BTreeError> {
+        let mut frames = self.frames.borrow_mut();
+        let id = node as u64;
+
+        if let Some(frame) = frames.frames.get_mut(&id) {
+            frame.used = true;
+            return Ok(frame.page.as_ptr() as Node);
+        }
+
+        let page = frames
+            .load(id)
+            .map_err(|err| BTreeError::PageRead(err.kind()))?;
+
+        // the node just read in, with its overflow pages, is kept while the others make room
+        if frames.reading {
+            frames.frames.get_mut(&id).unwrap().pins += 1;
+            frames.evict();
+            frames.frames.get_mut(&id).unwrap().pins -= 1;
+        }
+
+        Ok(page.as_ptr() as Node)
+    }
+
+    fn pin(&self, page: Node) {
+        let mut frames = self.frames.borrow_mut();
+        let id = frames.ids[&(page as usize)];
+
+        frames.frames.get_mut(&id).unwrap().pins += 1;
+    }
+
+    fn unpin(&self, page: Node) {
+        let mut frames = self.frames.borrow_mut();
+        let id = frames.ids[&(page as usize)];
+
+        frames.frames.get_mut(&id).unwrap().pins -= 1;
+    }
+
+    fn reference(&self, page: Node) -> Node {
+        self.frames.borrow().ids[&(page as usize)] as Node
+    }
+
+    fn mark_dirty(&self, page: Node) {
+        let mut frames = self.frames.borrow_mut();
+        let id = frames.ids[&(page as usize)];
+
+        frames.frames.get_mut(&id).unwrap().dirty = true;
+    }
+
+    fn evict(&self) {
+        self.frames.borrow_mut().evict();
+    }
+}
+
+impl<const P: usize> Debug for BufferPool<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("page_size", &P)
+            .field("capacity", &self.capacity())
+            .field("cached_pages", &self.cached_pages())
+            .finish()
+    }
+}
+
+impl<const P: usize> Drop for BufferPool<P> {
+    fn drop(&mut self) {
+        let frames = self.frames.get_mut();
+        let ids: Vec<_> = frames.frames.keys().copied().collect();
+
+        for id in ids {
+            frames.unload(id);
+        }
+    }
+}
+
+/// A tree kept in a single data file. Its nodes are read in by a [`BufferPool`] as they are
+/// needed and written back when they are let go of or on `flush`, so the tree can be much larger
+/// than memory. In the file, pages refer to each other by their 64-bit id: the links between
+/// neighbouring leaves, the children in branch slots, and keys too long for their page, which are
+/// chained through overflow pages of their own. Pages that are freed are used again before the
+/// file grows.
+///
+/// Values are stored as they are, so this is meant for `insert_value`. A flush is not atomic, a
+/// crash in the middle of one, or a node written back in between, can leave the file broken.
+pub struct FileTree<const P: usize = PAGE_SIZE> {
+    tree: BTree<(), P, BufferPool<P>>,
+}
+
+impl<const P: usize> FileTree<P> {
+    /// Creates a data file at `path` holding an empty tree, replacing whatever was there
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let pool = BufferPool::new(PageFile {
+            file,
+            pages: 1,
+            free: vec![],
+            linked: 0,
+        });
+
+        let mut tree = Self {
+            tree: BTree::new_in(pool),
+        };
+
+        tree.flush()?;
+        Ok(tree)
+    }
+
+    /// Opens the tree in the data file at `path`. It has to have been written with pages of `P`
+    /// bytes on a machine of the same endianness. Only the header and the free list are read
+    /// here, nodes follow as the tree gets to them
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut file = PageFile::<P> {
+            file,
+            pages: 1,
+            free: vec![],
+            linked: 0,
+        };
+
+        let mut header = [0; MAGIC.len() + HEADER_WORDS * size_of::<u64>()];
+        file.read(0, &mut header)?;
+
+        if header[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a B-tree data file".to_owned()));
+        }
+
+        let [page_size, root, height, pages, free, split_window] =
+            std::array::from_fn(|word| read_word(&header, MAGIC.len() + word * size_of::<u64>()));
+
+        if page_size != P as u64 {
+            return Err(invalid(format!(
+                "file has pages of {page_size} bytes instead of {P}"
+            )));
+        }
+
+        file.pages = pages;
+        file.check(root)?;
+
+        let mut next = free;
+
+        while next != NO_PAGE {
+            file.check(next)?;
+
+            if file.free.len() as u64 >= pages {
+                return Err(invalid("free list runs in a circle".to_owned()));
+            }
+
+            file.free.push(next);
+
+            let mut word = [0; size_of::<u64>()];
+            file.read(next, &mut word)?;
+            next = u64::from_ne_bytes(word);
+        }
+
+        file.free.reverse();
+        file.linked = file.free.len();
+
+        let mut tree = BTree::from_root(root as Node, height as usize, BufferPool::new(file));
+        tree.set_split_window(split_window as usize);
+
+        Ok(Self { tree })
+    }
+
+    /// The tree to read from. What it hands out borrows from nodes in the pool, so the nodes it
+    /// reads in stay until the next change, flush or call to this. `get` and `range` copy what
+    /// they read instead, and keep to the capacity of the pool
+    pub fn tree(&mut self) -> &BTree<(), P, BufferPool<P>> {
+        self.tree.allocator().evict();
+        &self.tree
+    }
+
+    /// The tree to change. Nothing reaches the file before the next `flush`, unless a changed
+    /// node is let go of to make room
+    pub fn tree_mut(&mut self) -> &mut BTree<(), P, BufferPool<P>> {
+        &mut self.tree
+    }
+
+    /// How many pages to keep in memory. Reading through `tree` only ever adds pages, they are let
+    /// go of on the next change or flush
+    pub fn set_cache_size(&mut self, pages: usize) {
+        let pool = self.tree.allocator();
+        pool.frames.borrow_mut().capacity = pages;
+        pool.evict();
+    }
+
+    /// Nodes and overflow pages currently in memory
+    pub fn cached_pages(&self) -> usize {
+        self.tree.allocator().cached_pages()
+    }
+
+    /// A copy of the bytes stored for `key`. The nodes on the way there may take the place of
+    /// others in the pool
+    pub fn get<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Result<Option<Vec<u8>>, BTreeError> {
+        let pool = self.tree.allocator();
+        pool.reading(|| Ok(self.tree.get_value(key)?.map(<[u8]>::to_vec)))
+    }
+
+    /// Copies of the entries with keys inside `range`, like [`BTree::range`] with inline values.
+    /// Only the leaves at either end are pinned, the others may take the place of each other in
+    /// the pool. A node that can't be read ends the range with an error
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> FileRange<'_, P> {
+        let pool = self.tree.allocator();
+
+        FileRange {
+            inner: Some(pool.reading(|| self.tree.range(range).inline_values())),
+            pool,
+        }
+    }
+
+    /// Pages in the file, including the header and the free ones
+    pub fn pages(&self) -> u64 {
+        self.tree.allocator().frames.borrow().file.pages
+    }
+
+    /// Pages in the file waiting to be used again
+    pub fn free_pages(&self) -> usize {
+        self.tree.allocator().frames.borrow().file.free.len()
+    }
+
+    /// Writes every node that changed since it was last read or written, then the header, and
+    /// waits for the file to be synced. Afterwards the pool is trimmed to its capacity. Returns
+    /// how many nodes were written
+    pub fn flush(&mut self) -> io::Result<usize> {
+        let pool = self.tree.allocator();
+        let written = pool.flush(
+            self.tree.root(),
+            self.tree.get_height(),
+            self.tree.split_window(),
+        )?;
+
+        pool.evict();
+        Ok(written)
+    }
+}
+
+impl<const P: usize> Debug for FileTree<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileTree")
+            .field("page_size", &P)
+            .field("height", &self.tree.get_height())
+            .field("pages", &self.pages())
+            .field("free_pages", &self.free_pages())
+            .field("cached_pages", &self.cached_pages())
+            .finish()
+    }
+}
+
+/// Entries of a [`FileTree`], copied out of their leaves. Created by [`FileTree::range`].
+pub struct FileRange<'a, const P: usize = PAGE_SIZE> {
+    // gone once the range is over
+    inner: Option<InlineValues<'a, (), P, BufferPool<P>>>,
+    pool: &'a BufferPool<P>,
+}
+
+impl<const P: usize> FileRange<'_, P> {
+    // the next entry from the back or the front, copied before anything else is read in
+    fn read(&mut self, back: bool) -> Option<<Self as Iterator>::Item> {
+        let inner = self.inner.as_mut()?;
+        let entry = self.pool.reading(|| {
+            let entry = match back {
+                true => inner.next_back(),
+                false => inner.next(),
+            };
+
+            entry.map(|(key, value)| (key.into_owned(), value.to_vec()))
+        });
+
+        match entry {
+            Some(entry) => Some(Ok(entry)),
+            None => self.inner.take()?.take_error().map(Err),
+        }
+    }
+}
+
+impl<const P: usize> Iterator for FileRange<'_, P> {
+    type Item = Result<(Vec<u8>, Vec<u8>), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read(false)
+    }
+}
+
+impl<const P: usize> DoubleEndedIterator for FileRange<'_, P> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.read(true)
+    }
+}
+
+impl<const P: usize> Drop for FileTree<P> {
+    fn drop(&mut self) {
+        // like a `BufWriter`, errors are lost here. Call `flush` to see them
+        let _ = self.flush();
+    }
+}
@@ -1,309 +1,716 @@
-use std::{fmt::Debug, marker::PhantomData, ptr::NonNull, u16, usize};
+use std::{borrow::Cow, fmt::Debug, iter, marker::PhantomData, ptr::NonNull, u16, usize};
 
 use crate::{
-    btree::{InsertResultIntern, Node},
-    flex::{Flex, FlexHead, SlotNode, DATA_LEN},
-    slotted_leaf::SlottedLeaf,
-    PTR_SIZE,
+    btree::{BTreeError, InsertResultIntern, Node},
+    flex::{
+        fences_len, first_bytes, split_candidates, within_fences, Flex, SlotNode, MAX_FENCES_LEN,
+        SLOT_SIZE,
+    },
+    page_alloc::PageAllocator,
+    slotted_leaf::{FromValue, SlottedLeaf},
+    PAGE_SIZE, PTR_SIZE,
 };
 
-pub struct SlottedBranch<T: Debug> {
-    pub header: FlexHead,
-    pub data: Flex,
+#[repr(C)]
+pub struct SlottedBranch<T: Debug, const P: usize = PAGE_SIZE> {
+    pub data: Flex<P>,
     boo: PhantomData<T>,
 }
 
-impl<T: Debug> SlottedBranch<T> {
-    pub fn new(left: Node, right: Node, separator: &str) -> Self {
+impl<T: Debug, const P: usize> SlottedBranch<T, P> {
+    /// A branch over two children. `separator` is stored as it is, so it has to come from
+    /// `Flex::store_key`
+    pub fn new(left: Node, right: Node, separator: &[u8]) -> Self {
         let mut new_self = Self {
-            header: FlexHead::new(std::ptr::NonNull::new(Some(right).unwrap())),
-            data: Flex::new(),
+            data: Flex::new(std::ptr::NonNull::new(Some(right).unwrap()), 0),
             boo: PhantomData,
         };
 
-        let node = new_self
-            .data
-            .add_heap_entry(&mut new_self.header, separator, left);
+        let node = new_self.data.add_heap_entry(separator, left);
 
-        new_self.data.insert_stack(&mut new_self.header, 0, node);
+        new_self.data.insert_stack(0, node);
 
-        let (nodes, _) = new_self.data.interpret_mut(&new_self.header);
+        let (nodes, _) = new_self.data.interpret_mut();
 
         nodes[0] = node;
         new_self
     }
 
+    /// An empty branch for the keys from `lower` up to, but not including, `upper`
+    fn with_fences(right: Option<NonNull<()>>, lower: &[u8], upper: Option<&[u8]>) -> Self {
+        let mut new_self = Self {
+            data: Flex::new(right, 0),
+            boo: PhantomData,
+        };
+
+        new_self.data.set_fences(lower, upper);
+        new_self
+    }
+
     fn new_from_range(
         range: &[SlotNode],
         src: &Self,
         right: Option<NonNull<()>>,
-        extra_node: Option<&SlotNode>,
-        extra_slot: (&str, Node),
+        extra_slot: (&[u8], Node),
+        lower: &[u8],
+        upper: Option<&[u8]>,
     ) -> Self {
-        let mut new_self = Self {
-            header: FlexHead::new(right),
-            data: Flex::new(),
-            boo: PhantomData,
-        };
+        let mut new_self = Self::with_fences(right, lower, upper);
 
         for node in range {
-            let (key, value) =
-                src.data
-                    .get_overflow_heap_entry(&mut new_self.header, node, extra_slot);
+            let (key, value) = src.data.get_overflow_heap_area(node, extra_slot);
 
-            let new_node = new_self
-                .data
-                .add_heap_entry(&mut new_self.header, key, value as Node);
+            let new_node = new_self.data.add_heap_entry(key, value as Node);
 
-            let count = new_self.header.node_count.into();
-            new_self
-                .data
-                .insert_stack(&mut new_self.header, count, new_node);
+            let count = new_self.data.head().node_count.into();
+            new_self.data.insert_stack(count, new_node);
         }
 
-        if let Some(node) = extra_node {
-            let (str, ptr) = src
-                .data
-                .get_overflow_heap_entry(&src.header, node, extra_slot);
+        new_self
+    }
 
-            let new_node = new_self.data.add_heap_entry(&mut new_self.header, str, ptr);
+    /// A branch with these entries. Their keys are stored as they are, so they have to come from
+    /// `Flex::store_key` or from other branches
+    pub(crate) fn from_entries<'a>(
+        entries: impl Iterator<Item = (&'a [u8], Node)>,
+        right: Option<NonNull<()>>,
+        lower: &[u8],
+        upper: Option<&[u8]>,
+    ) -> Self {
+        let mut new_self = Self::with_fences(right, lower, upper);
 
-            let count = new_self.header.node_count.into();
-            new_self
-                .data
-                .insert_stack(&mut new_self.header, count, new_node);
+        for (key, value) in entries {
+            let new_node = new_self.data.add_heap_entry(key, value);
+
+            let count = new_self.data.head().node_count.into();
+            new_self.data.insert_stack(count, new_node);
         }
 
         new_self
     }
 
     pub fn size(&self) -> usize {
-        self.header.node_count as usize
+        self.data.head().node_count as usize
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.data.live_bytes()
+    }
+
+    pub fn is_underfull(&self) -> bool {
+        self.live_bytes() < Flex::<P>::UNDERFULL_BYTES
     }
 
     pub fn unused_bytes(&self) -> usize {
-        self.header.key_pos as usize - self.header.node_count as usize * size_of::<SlotNode>()
+        self.data.head().key_pos as usize - self.data.head().node_count as usize * SLOT_SIZE
     }
 
-    pub fn payload_bytes(&self) -> usize {
-        DATA_LEN - self.header.node_count as usize
+    pub fn key_at(&self, index: usize) -> Cow<'_, [u8]> {
+        self.data.key_at(index)
     }
 
-    pub fn key_at(&self, index: usize) -> &str {
-        self.data.key_at(&self.header, index)
+    pub fn lower_fence(&self) -> &[u8] {
+        self.data.lower_fence()
     }
 
-    pub fn child_at(&self, index: usize) -> *mut () {
-        if index == self.header.node_count as usize {
-            return self.header.pointer.expect("Invalid Branch Layout").as_ptr();
+    pub fn upper_fence(&self) -> Option<&[u8]> {
+        self.data.upper_fence()
+    }
+
+    /// Makes sure every separator lies within the fences. Only done in debug builds, as it has to
+    /// look at the whole page
+    pub(crate) fn check_fences(&self) {
+        if !cfg!(debug_assertions) {
+            return;
         }
 
-        self.data.value_at(&self.header, index)
+        let (lower, upper) = (self.lower_fence(), self.upper_fence());
+
+        for (key, _) in self.entries() {
+            assert!(
+                within_fences(lower, upper, &key),
+                "Separator {key:?} outside of the fences {lower:?}..{upper:?}"
+            );
+        }
     }
 
-    pub fn can_fit(&self, key: &str) -> bool {
-        let new_entry_size = key.len() + PTR_SIZE + size_of::<SlotNode>();
+    pub fn entries(&self) -> impl Iterator<Item = (Cow<'_, [u8]>, Node)> {
+        (0..self.size()).map(|index| self.data.entry_at(index))
+    }
 
-        self.unused_bytes() >= new_entry_size
+    // the entries with their keys as they are stored, to be moved into another branch
+    fn stored_entries(&self) -> impl Iterator<Item = (&[u8], Node)> {
+        let (nodes, _) = self.data.interpret();
+        nodes.iter().map(|node| self.data.get_heap_area(node))
     }
 
-    pub fn get_raw(&mut self, at: u16) -> &u8 {
-        self.data.get_raw(at as usize - size_of::<FlexHead>())
+    /// Hands the overflow pages of every separator in the branch back to `alloc`. Done before the
+    /// page itself goes, the branch can't do that on drop as it doesn't know its allocator
+    pub(crate) fn release_keys<A: PageAllocator>(&self, alloc: &A) {
+        let (nodes, _) = self.data.interpret();
+
+        for node in nodes {
+            self.data.release_heap_key(node, alloc);
+        }
     }
 
-    fn get_upper_bound(&self, key: &str) -> usize {
-        let (nodes, _) = self.data.interpret(&self.header);
-        let mut slot_nr = 0;
+    /// Tells `alloc` this page changed
+    pub(crate) fn mark_dirty<A: PageAllocator>(&self, alloc: &A) {
+        alloc.mark_dirty(std::ptr::from_ref(self) as Node);
+    }
 
-        let mut u32_bytes = [0; 4];
-        let key_slice = if key.len() <= 4 { key } else { &key[0..4] };
+    pub fn child_at(&self, index: usize) -> *mut () {
+        self.try_child_at(index).expect("Invalid Branch Layout")
+    }
 
-        for (i, byte) in key_slice.bytes().enumerate() {
-            u32_bytes[i] = byte;
+    /// Like `child_at`, but reports a missing rightmost child instead of panicking
+    pub fn try_child_at(&self, index: usize) -> Result<*mut (), BTreeError> {
+        if index == self.data.head().node_count as usize {
+            return self
+                .data
+                .head()
+                .pointer
+                .map(NonNull::as_ptr)
+                .ok_or(BTreeError::InvalidLayout);
         }
 
-        let hint = u32::from_be_bytes(u32_bytes);
+        Ok(self.data.value_at(index))
+    }
 
-        for node in nodes {
-            let (node_key, _) = self.data.get_heap_entry(&self.header, node);
+    /// Page bytes taken up by a separator and its child pointer, including the slot. Also right for
+    /// a key area as a page stores it
+    pub fn entry_size(key: &[u8]) -> usize {
+        Flex::<P>::stored_key_len(key.len(), 0) + PTR_SIZE + SLOT_SIZE
+    }
 
-            if node.first_bytes >= hint {
-                if node_key > key {
-                    return slot_nr;
-                }
-            }
+    pub fn can_fit(&self, key: &[u8]) -> bool {
+        self.unused_bytes() >= Self::entry_size(key)
+    }
+
+    // whether an entry of `bytes` fits, closing the holes left behind by removals first if that is
+    // what it takes
+    fn make_room(&mut self, bytes: usize) -> bool {
+        if self.unused_bytes() >= bytes {
+            return true;
+        }
 
-            slot_nr += 1;
+        if self.unused_bytes() + self.data.fragmented_bytes() < bytes {
+            return false;
         }
 
-        slot_nr
+        self.data.compact();
+        true
     }
 
-    pub fn insert(&mut self, key: &str, value: Node, height: usize) -> InsertResultIntern {
-        let i = self.get_upper_bound(key);
+    pub fn get_raw(&mut self, at: u16) -> &u8 {
+        self.data.get_raw(at as usize)
+    }
 
-        let ptr = {
-            if i == self.header.node_count as usize {
-                self.header.pointer.expect("Invalid Branch Layout").as_ptr()
-            } else {
-                let (nodes, _) = self.data.interpret(&self.header);
-                self.data.get_heap_entry(&self.header, &nodes[i]).1
-            }
-        };
+    // keys equal to a separator live to its right, so this is the first separator that is larger
+    pub fn get_upper_bound(&self, key: &[u8]) -> usize {
+        self.data
+            .search(key, true, |node| self.data.cmp_heap_key(node, key))
+    }
+
+    pub fn insert<V: FromValue, A: PageAllocator>(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        height: usize,
+        replace: bool,
+        window: usize,
+        alloc: &A,
+    ) -> Result<InsertResultIntern<V>, BTreeError> {
+        let i = self.get_upper_bound(key);
+        let ptr = self.try_child_at(i)?;
 
         if height == 1 {
             // we have reached the bottom, this is a leaf
-            let leaf_ptr = unsafe { &mut *(ptr as *mut SlottedLeaf<T>) };
-            let res = leaf_ptr.insert(key, value);
+            let leaf_ptr = unsafe { &mut *(alloc.try_page(ptr)? as *mut SlottedLeaf<T, P>) };
+            let res = leaf_ptr.insert_with(key, value, replace, window, alloc)?;
 
-            let InsertResultIntern::Split(separator, node) = res else {
-                return res;
+            let InsertResultIntern::Split(separator, node, replaced) = res else {
+                return Ok(res);
             };
-            return self.insert_leaf_at(i, &separator, node);
+            return Ok(self
+                .insert_leaf_at(i, &separator, node, window, alloc)?
+                .with_replaced(replaced));
         }
 
         // further down we go...
-        let branch_ptr = unsafe { &mut *(ptr as *mut SlottedBranch<T>) };
-        let res = branch_ptr.insert(key, value, height - 1);
+        let branch_ptr = unsafe { &mut *(alloc.try_page(ptr)? as *mut SlottedBranch<T, P>) };
+        let res = branch_ptr.insert(key, value, height - 1, replace, window, alloc)?;
 
-        let InsertResultIntern::Split(separator, node) = res else {
-            return res;
+        let InsertResultIntern::Split(separator, node, replaced) = res else {
+            return Ok(res);
         };
-        return self.insert_at(i, &separator, node);
+        return Ok(self
+            .insert_at(i, &separator, node, window, alloc)?
+            .with_replaced(replaced));
     }
 
-    fn fix_leaf_insert(&mut self, index: usize, value: Node) -> Node {
-        if self.header.node_count as usize > index {
-            self.data.swap_ptr_at(&self.header, index, value)
-        } else {
-            self.header
-                .pointer
-                .replace(NonNull::new(value).unwrap())
-                .expect("Invalid Branch Layout")
-                .as_ptr()
-        }
+    fn fix_leaf_insert(&mut self, index: usize, value: Node) -> Result<Node, BTreeError> {
+        self.swap_child_at(index, value)
     }
 
-    fn insert_leaf_at(&mut self, index: usize, key: &str, value: Node) -> InsertResultIntern {
-        let value = self.fix_leaf_insert(index, value);
-        self.insert_at(index, key, value)
+    fn swap_child_at(&mut self, index: usize, value: Node) -> Result<Node, BTreeError> {
+        if self.data.head().node_count as usize > index {
+            return Ok(self.data.swap_ptr_at(index, value));
+        }
+
+        let value = NonNull::new(value).ok_or(BTreeError::InvalidLayout)?;
+
+        self.data
+            .head_mut()
+            .pointer
+            .replace(value)
+            .map(NonNull::as_ptr)
+            .ok_or(BTreeError::InvalidLayout)
     }
 
-    fn get_split<'a>(
-        &'a mut self,
-        overflow_node: &SlotNode,
-        new_slot: (&'a str, Node),
-    ) -> (usize, &'a str) {
-        let midpoint = (&self.header.node_count + 1) / 2;
-
-        return (
-            midpoint as usize,
-            self.data
-                .key_at_overflow(&self.header, midpoint as usize, new_slot, overflow_node),
-        );
+    pub(crate) fn insert_leaf_at<V, A: PageAllocator>(
+        &mut self,
+        index: usize,
+        key: &[u8],
+        value: Node,
+        window: usize,
+        alloc: &A,
+    ) -> Result<InsertResultIntern<V>, BTreeError> {
+        let value = self.fix_leaf_insert(index, value)?;
+
+        self.insert_at(index, key, value, window, alloc)
+            .inspect_err(|_| {
+                // put the old child back, so the page is as it was
+                let _ = self.fix_leaf_insert(index, value);
+            })
     }
 
-    fn insert_at(&mut self, index: usize, key: &str, value: Node) -> InsertResultIntern {
-        if self.can_fit(key) {
-            let node = self
-                .data
-                .add_heap_entry(&mut self.header, key, value as Node);
+    // the separator moving up is the shortest key among the slots up to `window` away from the
+    // middle. `nodes` are the slots the branch would have after the insert, with the new entry as
+    // the one starting at u16::MAX. Returns the index of the separator
+    fn get_split<'a>(
+        &'a self,
+        nodes: &[SlotNode],
+        new_slot: (&'a [u8], Node),
+        window: usize,
+    ) -> Result<(usize, Vec<u8>), BTreeError> {
+        let count = nodes.len() - 1;
+
+        // the separator moves up, so there has to be a slot for it besides the overflow, and one
+        // to its left
+        if count < 2 {
+            return Err(BTreeError::NoSeparator);
+        }
 
-            self.data.insert_stack(&mut self.header, index, node);
+        let key = |index: usize| self.data.get_overflow_heap_area(&nodes[index], new_slot).0;
 
-            return InsertResultIntern::Inserted;
+        // bytes[i] is what the first i entries take up
+        let mut bytes = vec![0];
+        for index in 0..=count {
+            bytes.push(bytes[index] + Self::entry_size(key(index)));
         }
 
-        let mut u32_bytes = [0; 4];
-        let key_slice = if key.len() <= 4 { key } else { &key[0..4] };
+        // the halves have to leave room for their fences. Right around the middle of a large page
+        // they always do, on small pages the closest slot that does is taken instead
+        let budget = Flex::<P>::DATA_LEN - MAX_FENCES_LEN;
+        let midpoint = (count + 1) / 2;
+        let fits = |&index: &usize| {
+            bytes[index] <= budget && bytes[count + 1] - bytes[index + 1] <= budget
+        };
 
-        for (i, byte) in key_slice.bytes().enumerate() {
-            u32_bytes[i] = byte;
-        }
+        let index = split_candidates(midpoint, window, 1, count - 1)
+            .filter(fits)
+            .min_by_key(|&index| Flex::<P>::key_len(0, key(index)))
+            .or_else(|| split_candidates(midpoint, count, 1, count - 1).find(fits))
+            .ok_or(BTreeError::NoSeparator)?;
 
-        let end_node = self.data.insert_stack_overflow(
-            &self.header,
-            index,
-            SlotNode::new(u16::MAX, u16::MAX, u32::from_be_bytes(u32_bytes)),
-        );
+        Ok((index, self.data.read_key(key(index)).into_owned()))
+    }
 
-        let (index, separator) = self.get_split(&end_node, (key, value));
+    /// Adds a separator with the child left of it. A full branch is split at one of the slots up
+    /// to `window` away from its middle, the left half goes into a page from `alloc`. So do the
+    /// overflow pages of a separator too long for the page
+    pub(crate) fn insert_at<V, A: PageAllocator>(
+        &mut self,
+        index: usize,
+        key: &[u8],
+        value: Node,
+        window: usize,
+        alloc: &A,
+    ) -> Result<InsertResultIntern<V>, BTreeError> {
+        let stored_key = Flex::<P>::store_key(&[], key, alloc)?;
+
+        if self.make_room(Self::entry_size(&stored_key)) {
+            let node = self.data.add_heap_entry(&stored_key, value as Node);
+
+            self.data.insert_stack(index, node);
+            self.mark_dirty(alloc);
+
+            return Ok(InsertResultIntern::Inserted);
+        }
+
+        // the new separator is not in the page if the split fails
+        let release = |err| {
+            Flex::<P>::release_key(&stored_key, alloc);
+            err
+        };
 
-        // save a copy of separator, since it currently lives inside self which will be destroyed
-        // later on
-        let separator = separator.to_owned();
+        // the split is worked out on a copy of the slots, so the page is left as it was if there
+        // is none
+        let mut nodes = self.data.interpret().0.to_vec();
+        nodes.insert(index, SlotNode::new(u16::MAX, u16::MAX, first_bytes(key)));
 
-        let (nodes, _) = self.data.interpret(&self.header);
+        let (index, separator) = self
+            .get_split(&nodes, (&stored_key, value), window)
+            .map_err(release)?;
 
         let (left_nodes, mid_node) = nodes.split_at(index);
         let (mid_node, right_nodes) = mid_node.split_at(1);
 
         // the key here is our separator. we already got that, so ignore
-        let (_, mid_val) =
-            self.data
-                .get_overflow_heap_entry(&self.header, &mid_node[0], (key, value));
+        let (mid_key, mid_val) = self
+            .data
+            .get_overflow_heap_area(&mid_node[0], (&stored_key, value));
+        let mid_key = mid_key.to_vec();
 
-        let left = SlottedBranch::new_from_range(
+        let left = Self::new_from_range(
             left_nodes,
             self,
-            Some(std::ptr::NonNull::new(mid_val as Node).unwrap()),
-            None,
-            (key, value),
+            Some(NonNull::new(mid_val as Node).ok_or(BTreeError::InvalidLayout)?),
+            (&stored_key, value),
+            self.lower_fence(),
+            Some(&separator),
         );
 
-        let right = SlottedBranch::new_from_range(
+        let right = Self::new_from_range(
             right_nodes,
             self,
-            self.header.pointer,
-            Some(&end_node),
-            (key, value),
+            self.data.head().pointer,
+            (&stored_key, value),
+            &separator,
+            self.upper_fence(),
         );
 
-        // become the right subtree
-        let _ = std::mem::replace(self, right);
+        left.check_fences();
+        right.check_fences();
+
+        // the left half gets its page first, so the branch is left as it was if there is none
+        let left_pointer = alloc.alloc_node(left).map_err(release)?;
 
-        let left_pointer = Box::into_raw(Box::new(left));
+        // become the right subtree. The other entries have all moved, but the separator only goes
+        // up as a key, the parent stores it anew
+        let _ = std::mem::replace(self, right);
+        self.mark_dirty(alloc);
+        Flex::<P>::release_key(&mid_key, alloc);
 
-        InsertResultIntern::Split(separator, left_pointer as Node)
+        Ok(InsertResultIntern::Split(
+            separator,
+            left_pointer as Node,
+            None,
+        ))
     }
 
-    pub fn get(&self, key: &str, height: usize) -> Option<*mut T> {
+    /// Removes `key` from the subtree. Children emptied by merges are handed back to `alloc`
+    pub fn remove<V: FromValue, A: PageAllocator>(
+        &mut self,
+        key: &[u8],
+        height: usize,
+        alloc: &A,
+    ) -> Option<V> {
+        // a child that is missing or can't be read holds nothing to remove
         let index = self.get_upper_bound(key);
+        let child = alloc.try_page(self.try_child_at(index).ok()?).ok()?;
 
-        let child = if index == self.size() {
-            self.header.pointer.expect("Invalid Branch Layout").as_ptr()
+        let (res, underfull) = if height == 1 {
+            let leaf = unsafe { &mut *(child as *mut SlottedLeaf<T, P>) };
+            let res = leaf.remove(key, alloc);
+            (res, leaf.is_underfull())
         } else {
-            self.data.value_at(&self.header, index)
+            let branch = unsafe { &mut *(child as *mut SlottedBranch<T, P>) };
+            let res = branch.remove(key, height - 1, alloc);
+            (res, branch.is_underfull())
+        };
+
+        if res.is_some() && underfull {
+            self.rebalance(index, height, alloc);
+        }
+
+        res
+    }
+
+    /// Removes the separator between the children at `index` and `index + 1`, leaving the left one
+    /// in charge of both ranges. Returns the right child, which is no longer referenced. The
+    /// overflow pages of the separator are left alone, it is up to the caller to move or release
+    /// them.
+    fn merge_children_at(&mut self, index: usize) -> Node {
+        let removed = self.data.remove_stack(index);
+        let (_, left) = self.data.get_heap_area(&removed);
+
+        self.swap_child_at(index, left)
+            .expect("Invalid Branch Layout")
+    }
+
+    // the separator at `index` as it is stored
+    fn stored_key_at(&self, index: usize) -> Vec<u8> {
+        let (nodes, _) = self.data.interpret();
+        self.data.heap_key_area(&nodes[index]).to_vec()
+    }
+
+    fn release_key_at<A: PageAllocator>(&self, index: usize, alloc: &A) {
+        let (nodes, _) = self.data.interpret();
+        self.data.release_heap_key(&nodes[index], alloc);
+    }
+
+    // `separator` is a key area as a branch stores it
+    fn can_replace_separator(&self, index: usize, separator: &[u8]) -> bool {
+        // the old entry is handed back by compacting the page, like any other hole
+        let (nodes, _) = self.data.interpret();
+        let old_bytes = (nodes[index].end - nodes[index].start) as usize + SLOT_SIZE;
+        let free = self.unused_bytes() + self.data.fragmented_bytes() + old_bytes;
+
+        free >= Self::entry_size(separator)
+    }
+
+    // puts the key area `separator` in place of the one at `index`. Like `merge_children_at`, the
+    // overflow pages of the old one are left to the caller
+    fn replace_separator(&mut self, index: usize, separator: &[u8]) {
+        let removed = self.data.remove_stack(index);
+        let (_, child) = self.data.get_heap_area(&removed);
+
+        let fits = self.make_room(Self::entry_size(separator));
+        debug_assert!(fits, "Checked by can_replace_separator");
+
+        let node = self.data.add_heap_entry(separator, child);
+        self.data.insert_stack(index, node);
+    }
+
+    /// Fixes up the underfull child at `index` by merging it with a neighbour or, if both do not
+    /// fit into a single page, by moving entries over from the neighbour.
+    fn rebalance<A: PageAllocator>(&mut self, index: usize, height: usize, alloc: &A) {
+        if self.size() == 0 {
+            // no neighbours to borrow from. We will be collapsed by our parent instead
+            return;
+        }
+
+        let left_index = if index == self.size() {
+            index - 1
+        } else {
+            index
+        };
+
+        // neighbours that are missing or can't be read are left alone, just like ones that don't
+        // fit
+        let (Ok(left_ptr), Ok(right_ptr)) = (
+            self.try_child_at(left_index),
+            self.try_child_at(left_index + 1),
+        ) else {
+            return;
+        };
+
+        let (Ok(left), Ok(right)) = (alloc.try_page(left_ptr), alloc.try_page(right_ptr)) else {
+            return;
         };
 
         if height == 1 {
-            let leaf = unsafe { &*(child as *mut SlottedLeaf<T>) };
-            leaf.get(key)
+            let left = unsafe { &mut *(left as *mut SlottedLeaf<T, P>) };
+            let right = unsafe { &mut *(right as *mut SlottedLeaf<T, P>) };
+
+            // leaves keep no separators, so the one between them goes away
+            if left.can_merge(right) {
+                left.merge(right, alloc);
+                self.release_key_at(left_index, alloc);
+                self.merge_children_at(left_index);
+                self.mark_dirty(alloc);
+                unsafe { alloc.free_node(right_ptr as *mut SlottedLeaf<T, P>) };
+                return;
+            }
+
+            let Some((new_left, new_right, separator)) = left.redistribute(right) else {
+                return;
+            };
+
+            // like the redistribution itself, this is left out if there are no pages for it
+            let Ok(separator) = Flex::<P>::store_key(&[], &separator, alloc) else {
+                return;
+            };
+
+            if self.can_replace_separator(left_index, &separator) {
+                *left = new_left;
+                *right = new_right;
+                self.release_key_at(left_index, alloc);
+                self.replace_separator(left_index, &separator);
+                left.mark_dirty(alloc);
+                right.mark_dirty(alloc);
+                self.mark_dirty(alloc);
+            } else {
+                Flex::<P>::release_key(&separator, alloc);
+            }
+
+            return;
+        }
+
+        // separators rotate through here between branches, so they are moved as they are stored
+        let left = unsafe { &mut *(left as *mut SlottedBranch<T, P>) };
+        let right = unsafe { &mut *(right as *mut SlottedBranch<T, P>) };
+        let separator = self.stored_key_at(left_index);
+
+        if left.can_merge(&separator, right) {
+            left.merge(&separator, right);
+            self.merge_children_at(left_index);
+            left.mark_dirty(alloc);
+            self.mark_dirty(alloc);
+            unsafe { alloc.free_node(right_ptr as *mut SlottedBranch<T, P>) };
+            return;
+        }
+
+        let Some((new_left, new_right, separator)) = left.redistribute(&separator, right) else {
+            return;
+        };
+
+        if self.can_replace_separator(left_index, &separator) {
+            *left = new_left;
+            *right = new_right;
+            self.replace_separator(left_index, &separator);
+            left.mark_dirty(alloc);
+            right.mark_dirty(alloc);
+            self.mark_dirty(alloc);
+        }
+    }
+
+    /// Whether `right` and the parent's `separator` fit behind the entries of `self`. A branch
+    /// missing its rightmost child has nothing to put under the separator and never does
+    pub fn can_merge(&self, separator: &[u8], right: &Self) -> bool {
+        let separator_bytes = Self::entry_size(separator);
+        let fence_bytes = fences_len(self.lower_fence(), right.upper_fence());
+
+        self.live_bytes() + separator_bytes + right.live_bytes() + fence_bytes
+            <= Flex::<P>::DATA_LEN
+            && self.try_child_at(self.size()).is_ok()
+    }
+
+    /// Pulls the parent's `separator`, a key area as a branch stores it, down and appends all of
+    /// `right` after it. Freeing `right` is up to the caller, its children now belong to `self`.
+    pub fn merge(&mut self, separator: &[u8], right: &Self) {
+        let last = self.child_at(self.size());
+
+        let merged = Self::from_entries(
+            self.stored_entries()
+                .chain(iter::once((separator, last)))
+                .chain(right.stored_entries()),
+            right.data.head().pointer,
+            self.lower_fence(),
+            right.upper_fence(),
+        );
+
+        let _ = std::mem::replace(self, merged);
+        self.check_fences();
+    }
+
+    /// Rotates entries through the parent so both branches end up holding about the same amount of
+    /// bytes. Returns the new pages and the separator to put into the parent, or `None` if a half
+    /// would not fit with its new fences or `self` is missing its rightmost child. Both separators
+    /// are key areas as a branch stores them.
+    pub fn redistribute(&self, separator: &[u8], right: &Self) -> Option<(Self, Self, Vec<u8>)> {
+        let last = self.try_child_at(self.size()).ok()?;
+
+        let entries: Vec<_> = self
+            .stored_entries()
+            .chain(iter::once((separator, last)))
+            .chain(right.stored_entries())
+            .collect();
+
+        let half = (self.live_bytes() + right.live_bytes()) / 2;
+
+        let mut mid_index = 0;
+        let mut left_bytes = 0;
+
+        while mid_index < entries.len() - 1 && left_bytes < half {
+            left_bytes += Self::entry_size(entries[mid_index].0);
+            mid_index += 1;
+        }
+
+        let (mid_key, mid_child) = entries[mid_index];
+        let fence = Flex::<P>::whole_key(&[], mid_key);
+
+        let left_entries = || entries[..mid_index].iter().copied();
+        let right_entries = || entries[mid_index + 1..].iter().copied();
+
+        if Self::rebuilt_bytes(left_entries(), self.lower_fence(), Some(&fence))
+            > Flex::<P>::DATA_LEN
+            || Self::rebuilt_bytes(right_entries(), &fence, right.upper_fence())
+                > Flex::<P>::DATA_LEN
+        {
+            return None;
+        }
+
+        let left = Self::from_entries(
+            left_entries(),
+            NonNull::new(mid_child),
+            self.lower_fence(),
+            Some(&fence),
+        );
+
+        let right_branch = Self::from_entries(
+            right_entries(),
+            right.data.head().pointer,
+            &fence,
+            right.upper_fence(),
+        );
+
+        left.check_fences();
+        right_branch.check_fences();
+
+        Some((left, right_branch, mid_key.to_owned()))
+    }
+
+    // page bytes a branch with these fences and entries would take up
+    fn rebuilt_bytes<'a>(
+        entries: impl Iterator<Item = (&'a [u8], Node)>,
+        lower: &[u8],
+        upper: Option<&[u8]>,
+    ) -> usize {
+        fences_len(lower, upper) + entries.map(|(key, _)| Self::entry_size(key)).sum::<usize>()
+    }
+
+    pub fn get<A: PageAllocator>(
+        &self,
+        key: &[u8],
+        height: usize,
+        alloc: &A,
+    ) -> Result<Option<*mut T>, BTreeError> {
+        let child = alloc.try_page(self.try_child_at(self.get_upper_bound(key))?)?;
+
+        if height == 1 {
+            let leaf = unsafe { &*(child as *mut SlottedLeaf<T, P>) };
+            Ok(leaf.get(key))
         } else {
-            let branch = unsafe { &*(child as *mut SlottedBranch<T>) };
-            branch.get(key, height - 1)
+            let branch = unsafe { &*(child as *mut SlottedBranch<T, P>) };
+            branch.get(key, height - 1, alloc)
         }
     }
 
-    pub fn print(&self) -> String {
+    /// The branch as a graphviz node. `node` is what its parent refers to it by
+    pub fn print(&self, node: Node) -> String {
         let mut contents = String::new();
         let mut vertices = String::new();
-        let self_ptr = std::ptr::from_ref(self) as usize;
+        let self_ptr = node as usize;
 
-        let (nodes, _) = self.data.interpret(&self.header);
+        let (nodes, _) = self.data.interpret();
         for node in nodes {
-            let (key, ptr) = self.data.get_heap_entry(&self.header, node);
+            let (key, ptr) = self.data.get_heap_entry(node);
             let value_box_id = ptr as usize;
 
             vertices.push_str(&format!("{self_ptr}:s{value_box_id} -> {value_box_id}\n"));
             contents.push_str(&format!(
                 "<s{}> | '{}' | ",
                 value_box_id,
-                key.replace("\n", "\\n").replace("\"", "\\\"")
+                String::from_utf8_lossy(&key)
+                    .replace("\n", "\\n")
+                    .replace("\"", "\\\"")
             ));
         }
 
-        let Some(last) = self.header.pointer else {
+        let Some(last) = self.data.head().pointer else {
             panic!("Invalid Branch Layout");
         };
 
@@ -313,12 +720,42 @@ impl<T: Debug> SlottedBranch<T> {
 
         contents.push_str(&format!("<s{last_id}>"));
 
-        format!(
-            "{}[label=\"{contents}\"]\n{vertices}\n",
-            std::ptr::from_ref(self) as usize,
-        )
+        format!("{}[label=\"{contents}\"]\n{vertices}\n", self_ptr,)
     }
 }
 
-// no drop implementation here, the node itself has no idea what height it's on. So we need to rely
-// on the BTree Drop
+#[cfg(test)]
+mod branch_tests {
+    use super::SlottedBranch;
+    use crate::{
+        btree::{BTreeError, Node},
+        flex::SlotNode,
+        page_alloc::Heap,
+    };
+
+    #[test]
+    fn no_split_point() {
+        let key = |i: usize| format!("{i:060}").into_bytes();
+        let mut branch = SlottedBranch::<(), 1024>::new(1 as Node, 2 as Node, &key(0));
+        let mut i = 1;
+
+        while branch.can_fit(&key(i)) {
+            let index = branch.size();
+            branch
+                .insert_at::<Node, _>(index, &key(i), (i + 2) as Node, 0, &Heap)
+                .unwrap();
+            i += 1;
+        }
+
+        // slots for three pages, more than both halves can take with their fences
+        let (slots, _) = branch.data.interpret();
+        let mut nodes = [slots, slots, slots].concat();
+        nodes.push(SlotNode::new(u16::MAX, u16::MAX, 0));
+
+        let new_key = key(i);
+        assert_eq!(
+            branch.get_split(&nodes, (&new_key, 3 as Node), 1),
+            Err(BTreeError::NoSeparator)
+        );
+    }
+}
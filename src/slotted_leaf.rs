@@ -1,130 +1,422 @@
-use std::{fmt::Debug, iter, marker::PhantomData, ptr::NonNull};
+use std::{borrow::Cow, fmt::Debug, iter, marker::PhantomData, ptr::NonNull};
 
 use crate::{
-    btree::{InsertResultIntern, Node},
-    flex::{Flex, FlexHead, SlotNode, DATA_LEN},
-    PTR_SIZE,
+    btree::{BTreeError, InsertResultIntern, Node},
+    flex::{
+        fence_prefix_len, fences_len, first_bytes, ptr_from_bytes, ptr_to_bytes, split_candidates,
+        within_fences, Flex, SlotNode, DEFAULT_SPLIT_WINDOW, MAX_FENCES_LEN, SLOT_SIZE,
+        VALUE_LEN_SIZE,
+    },
+    page_alloc::{Heap, PageAllocator},
+    PAGE_SIZE,
 };
 
-pub struct SlottedLeaf<T: Debug> {
-    header: FlexHead,
-    data: Flex,
+/// Something that can be read back out of the value bytes of a leaf entry
+pub trait FromValue: Sized {
+    /// None if the bytes don't make up a `Self`
+    fn from_value(bytes: &[u8]) -> Option<Self>;
+}
+
+impl<U> FromValue for *mut U {
+    fn from_value(bytes: &[u8]) -> Option<Self> {
+        ptr_from_bytes(bytes).map(|ptr| ptr as *mut U)
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+// an entry the way a leaf stores it: its key area as `Flex::store_key` made it for the prefix of
+// the leaf, and its value. Entries are moved between leaves like this, so the overflow pages of
+// their keys are never read or copied
+#[derive(Clone, Copy)]
+struct StoredEntry<'a> {
+    prefix: &'a [u8],
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+#[repr(C)]
+pub struct SlottedLeaf<T: Debug, const P: usize = PAGE_SIZE> {
+    pub(crate) data: Flex<P>,
     boo: PhantomData<T>,
 }
 
-impl<T: Debug> SlottedLeaf<T> {
+impl<T: Debug, const P: usize> SlottedLeaf<T, P> {
+    /// Values longer than this are not stored inline. Splits are balanced by bytes, so any two
+    /// halves of a page stay well within a page as long as single entries are this small
+    pub const MAX_VALUE_LEN: usize = Flex::<P>::DATA_LEN / 8;
+
+    // the left sibling is kept in the last bytes of the page instead of the header, which
+    // branches share and have no use for it
+    const PREV_LEN: usize = size_of::<Option<NonNull<()>>>();
+
+    /// Where in the page the link to the left sibling is
+    pub(crate) const PREV_AT: usize = P - Self::PREV_LEN;
+
+    /// Page bytes left for slots, heap and fences
+    const CAPACITY: usize = Flex::<P>::DATA_LEN - Self::PREV_LEN;
+
     pub fn new() -> Self {
         let new_self = Self {
-            header: FlexHead::new(None),
-            data: Flex::new(),
+            data: Flex::new(None, Self::PREV_LEN),
             boo: PhantomData,
         };
 
         new_self
     }
 
+    /// An empty leaf for the keys from `lower` up to, but not including, `upper`. The bytes the
+    /// two have in common are kept once as the prefix of the page instead of in every key
+    pub fn with_fences(lower: &[u8], upper: Option<&[u8]>) -> Self {
+        let mut new_self = Self::new();
+        new_self.data.set_fences(lower, upper);
+        new_self.data.head_mut().prefix_len = fence_prefix_len(lower, upper) as u16;
+
+        new_self
+    }
+
     fn new_from_range(
         range: &[SlotNode],
         src: &Self,
-        extra_node: Option<&SlotNode>,
-        extra_slot: (&str, Node),
+        extra_slot: (&[u8], &[u8]),
+        lower: &[u8],
+        upper: Option<&[u8]>,
     ) -> Self {
         // initialize with empty pointer slot, the caller will have to re-bend the pointers
-        let mut new_self = Self {
-            header: FlexHead::new(None),
-            data: Flex::new(),
-            boo: PhantomData,
-        };
+        let mut new_self = Self::with_fences(lower, upper);
 
         for node in range {
-            let (key, value) = src
-                .data
-                .get_overflow_heap_entry(&src.header, node, extra_slot);
+            let (key, value) = src.data.get_overflow_value_area(node, extra_slot);
 
-            let new_node = new_self
-                .data
-                .add_heap_entry(&mut new_self.header, key, value);
-
-            let count = new_self.header.node_count.into();
-            new_self
-                .data
-                .insert_stack(&mut new_self.header, count, new_node);
+            new_self.push_moved(src.prefix(), key, value);
         }
 
-        if let Some(node) = extra_node {
-            let (key, value) = src
-                .data
-                .get_overflow_heap_entry(&src.header, node, extra_slot);
+        new_self
+    }
 
-            let new_node = new_self
-                .data
-                .add_heap_entry(&mut new_self.header, key, value);
+    fn from_entries<'a>(
+        entries: impl Iterator<Item = StoredEntry<'a>>,
+        lower: &[u8],
+        upper: Option<&[u8]>,
+    ) -> Self {
+        let mut new_self = Self::with_fences(lower, upper);
 
-            let count = new_self.header.node_count.into();
-            new_self
-                .data
-                .insert_stack(&mut new_self.header, count, new_node);
+        for entry in entries {
+            new_self.push_moved(entry.prefix, entry.key, entry.value);
         }
 
         new_self
     }
 
+    /// Appends an entry behind all others. `key` has to be larger than every key in the leaf. If it
+    /// is too long for the page, it goes into overflow pages from `alloc`
+    pub(crate) fn push<A: PageAllocator>(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        alloc: &A,
+    ) -> Result<(), BTreeError> {
+        debug_assert!(key.starts_with(self.prefix()), "Key is outside the fences");
+
+        let suffix = &key[self.data.head().prefix_len as usize..];
+        let key = Flex::<P>::store_key(self.prefix(), suffix, alloc)?;
+        self.push_area(&key, value);
+
+        Ok(())
+    }
+
+    // appends an entry whose key is stored as `key` in a leaf with `prefix`
+    fn push_moved(&mut self, prefix: &[u8], key: &[u8], value: &[u8]) {
+        let key = self.data.moved_key(prefix, key);
+        self.push_area(&key, value);
+    }
+
+    fn push_area(&mut self, key: &[u8], value: &[u8]) {
+        let new_node = self.data.add_value_entry(key, value);
+
+        let count = self.data.head().node_count.into();
+        self.data.insert_stack(count, new_node);
+    }
+
+    // the entries as they are stored, to be moved into another leaf
+    fn stored_entries(&self) -> impl Iterator<Item = StoredEntry<'_>> {
+        (0..self.size()).map(|index| {
+            let (key, value) = self.data.value_area_at(index);
+
+            StoredEntry {
+                prefix: self.prefix(),
+                key,
+                value,
+            }
+        })
+    }
+
+    // page bytes `entry` would take up in a leaf with a prefix of `prefix_len` bytes
+    fn moved_size(entry: &StoredEntry, prefix_len: usize) -> usize {
+        let key_len = Flex::<P>::key_len(entry.prefix.len(), entry.key);
+
+        Flex::<P>::stored_key_len(key_len, prefix_len)
+            + VALUE_LEN_SIZE
+            + entry.value.len()
+            + SLOT_SIZE
+    }
+
+    /// The length of the longest key in the leaf
+    pub(crate) fn longest_key(&self) -> usize {
+        self.stored_entries()
+            .map(|entry| Flex::<P>::key_len(entry.prefix.len(), entry.key))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Hands the overflow pages of every key in the leaf back to `alloc`. Done before the page
+    /// itself goes, the leaf can't do that on drop as it doesn't know its allocator
+    pub(crate) fn release_keys<A: PageAllocator>(&self, alloc: &A) {
+        let (nodes, _) = self.data.interpret();
+
+        for node in nodes {
+            self.data.release_value_key(node, alloc);
+        }
+    }
+
+    /// Rebuilds the leaf with new fences. Used by the bulk load, which only learns the upper fence
+    /// of a leaf once it is full
+    pub(crate) fn set_fences(&mut self, lower: &[u8], upper: Option<&[u8]>) {
+        let mut fenced = Self::from_entries(self.stored_entries(), lower, upper);
+        fenced.data.head_mut().pointer = self.data.head().pointer;
+        fenced.set_prev_leaf(self.prev_leaf());
+
+        let _ = std::mem::replace(self, fenced);
+    }
+
+    pub fn lower_fence(&self) -> &[u8] {
+        self.data.lower_fence()
+    }
+
+    pub fn upper_fence(&self) -> Option<&[u8]> {
+        self.data.upper_fence()
+    }
+
+    /// The bytes shared by every key in the leaf, which are not stored with the keys
+    pub fn prefix(&self) -> &[u8] {
+        self.data.prefix()
+    }
+
+    /// Makes sure every key lies within the fences. Only done in debug builds, as it has to look at
+    /// the whole page
+    pub(crate) fn check_fences(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+
+        let (lower, upper) = (self.lower_fence(), self.upper_fence());
+
+        for index in 0..self.size() {
+            let key = self.key_at(index);
+            assert!(
+                within_fences(lower, upper, &key),
+                "Key {key:?} outside of the fences {lower:?}..{upper:?}"
+            );
+        }
+    }
+
+    /// Makes `right` the next leaf after `left`. Both are nodes as `alloc` handed them out
+    pub(crate) fn link<A: PageAllocator>(left: Node, right: Node, alloc: &A) {
+        let left_page = unsafe { &mut *(alloc.page(left) as *mut Self) };
+        let right_page = unsafe { &mut *(alloc.page(right) as *mut Self) };
+
+        left_page.data.head_mut().pointer = NonNull::new(right);
+        right_page.set_prev_leaf(NonNull::new(left));
+
+        left_page.mark_dirty(alloc);
+        right_page.mark_dirty(alloc);
+    }
+
+    /// Tells `alloc` this page changed
+    pub(crate) fn mark_dirty<A: PageAllocator>(&self, alloc: &A) {
+        alloc.mark_dirty(std::ptr::from_ref(self) as Node);
+    }
+
     #[inline(always)]
     pub fn size(&self) -> usize {
-        self.header.node_count as usize
+        self.data.head().node_count as usize
     }
 
     pub fn unused_bytes(&self) -> usize {
-        self.header.key_pos as usize - self.header.node_count as usize * size_of::<SlotNode>()
+        self.data.head().key_pos as usize - self.data.head().node_count as usize * SLOT_SIZE
     }
 
+    /// Heap bytes below the fences, including holes left behind by removals
     pub fn payload_bytes(&self) -> usize {
-        DATA_LEN - self.header.key_pos as usize
+        (self.data.head().fences - self.data.head().key_pos) as usize
     }
 
-    pub fn key_at(&self, index: usize) -> &str {
-        self.data.key_at(&self.header, index)
+    pub fn live_bytes(&self) -> usize {
+        self.data.live_bytes()
     }
 
-    pub fn value_at(&self, index: usize) -> *mut T {
-        self.data.value_at(&self.header, index) as *mut T
+    pub fn is_underfull(&self) -> bool {
+        self.live_bytes() < Flex::<P>::UNDERFULL_BYTES
     }
 
-    pub fn can_fit(&self, key: &str) -> bool {
-        let new_entry_size = key.len() + PTR_SIZE + size_of::<SlotNode>();
+    /// The key at `index`. It only has to be put together anew on a page with a prefix, or read
+    /// back if it is kept in overflow pages
+    pub fn key_at(&self, index: usize) -> Cow<'_, [u8]> {
+        Flex::<P>::whole_key(self.prefix(), self.data.value_area_at(index).0)
+    }
 
-        self.unused_bytes() >= new_entry_size
+    /// What is stored of the key at `index`, everything behind the prefix of the page
+    pub fn suffix_at(&self, index: usize) -> Cow<'_, [u8]> {
+        self.data.value_entry_at(index).0
     }
 
-    pub fn get_raw(&mut self, at: u16) -> &u8 {
-        self.data.get_raw(at as usize - size_of::<FlexHead>())
+    /// Whether the key at `index` is `key`, without putting the whole key together
+    pub fn is_key_at(&self, index: usize, key: &[u8]) -> bool {
+        key.strip_prefix(self.prefix())
+            .is_some_and(|suffix| self.is_suffix_at(index, suffix))
+    }
+
+    fn is_suffix_at(&self, index: usize, suffix: &[u8]) -> bool {
+        let (nodes, _) = self.data.interpret();
+        self.data.cmp_value_key(&nodes[index], suffix).is_eq()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (Cow<'_, [u8]>, &[u8])> {
+        (0..self.size()).map(|index| (self.key_at(index), self.value_bytes_at(index)))
+    }
+
+    /// The pointer stored at `index`, None if the entry holds a value of another size
+    pub fn value_at(&self, index: usize) -> Option<*mut T> {
+        FromValue::from_value(self.value_bytes_at(index))
+    }
+
+    pub fn value_bytes_at(&self, index: usize) -> &[u8] {
+        self.data.value_area_at(index).1
+    }
+
+    /// Puts `value` in place of the pointer at `index`. A value of another size is left alone
+    pub fn swap_value_at(&mut self, index: usize, value: *mut T) -> Option<*mut T> {
+        let old = self.value_at(index)?;
+        self.data
+            .overwrite_value_at(index, &ptr_to_bytes(value as Node));
+        Some(old)
     }
 
-    fn get_upper_bound(&self, key: &str) -> usize {
-        let (nodes, _) = self.data.interpret(&self.header);
-        let mut slot_nr = 0;
+    pub fn next_leaf(&self) -> Option<NonNull<()>> {
+        self.data.head().pointer
+    }
 
-        let mut u32_bytes = [0; 4];
-        let key_slice = if key.len() <= 4 { key } else { &key[0..4] };
+    pub fn prev_leaf(&self) -> Option<NonNull<()>> {
+        let link = usize::from_ne_bytes(self.data.reserved().try_into().unwrap());
+        NonNull::new(link as Node)
+    }
 
-        for (i, byte) in key_slice.bytes().enumerate() {
-            u32_bytes[i] = byte;
+    fn set_prev_leaf(&mut self, prev: Option<NonNull<()>>) {
+        let link = prev.map_or(0, |prev| prev.as_ptr() as usize);
+        self.data
+            .reserved_mut()
+            .copy_from_slice(&link.to_ne_bytes());
+    }
+
+    // point the back link of the leaf at `next` (if there is one) to `prev`. Both are nodes as
+    // `alloc` handed them out
+    fn set_prev<A: PageAllocator>(next: Option<NonNull<()>>, prev: Node, alloc: &A) {
+        if let Some(next) = next {
+            let next = unsafe { &mut *(alloc.page(next.as_ptr()) as *mut Self) };
+            next.set_prev_leaf(NonNull::new(prev));
+            next.mark_dirty(alloc);
         }
+    }
 
-        let hint = u32::from_be_bytes(u32_bytes);
+    /// Page bytes taken up by an entry in a leaf without a prefix, including its slot. Also right
+    /// for a key area as a page stores it
+    pub fn entry_size(key: &[u8], value: &[u8]) -> usize {
+        Flex::<P>::stored_key_len(key.len(), 0) + VALUE_LEN_SIZE + value.len() + SLOT_SIZE
+    }
 
-        for node in nodes {
-            if node.first_bytes >= hint {
-                let (node_key, _) = self.data.get_heap_entry(&self.header, node);
-                if node_key >= key {
-                    return slot_nr;
-                }
-            }
+    pub fn can_fit(&self, key: &[u8], value: &[u8]) -> bool {
+        let prefix_len = match key.starts_with(self.prefix()) {
+            true => self.prefix().len(),
+            false => 0,
+        };
+
+        self.unused_bytes()
+            >= Flex::<P>::stored_key_len(key.len(), prefix_len)
+                + VALUE_LEN_SIZE
+                + value.len()
+                + SLOT_SIZE
+    }
+
+    // whether an entry of `bytes` fits, closing the holes left behind by removals first if that is
+    // what it takes
+    fn make_room(&mut self, bytes: usize) -> bool {
+        if self.unused_bytes() >= bytes {
+            return true;
+        }
 
-            slot_nr += 1;
+        if self.unused_bytes() + self.data.fragmented_bytes() < bytes {
+            return false;
         }
 
-        slot_nr
+        self.data.compact();
+        true
+    }
+
+    // page bytes a leaf with these fences and entries would take up
+    fn rebuilt_bytes<'a>(
+        entries: impl Iterator<Item = StoredEntry<'a>>,
+        lower: &[u8],
+        upper: Option<&[u8]>,
+    ) -> usize {
+        let prefix_len = fence_prefix_len(lower, upper);
+
+        fences_len(lower, upper)
+            + entries
+                .map(|entry| Self::moved_size(&entry, prefix_len))
+                .sum::<usize>()
+    }
+
+    pub fn get_raw(&mut self, at: u16) -> &u8 {
+        self.data.get_raw(at as usize)
+    }
+
+    pub fn get_upper_bound(&self, key: &[u8]) -> usize {
+        match self.strip_prefix(key) {
+            Ok(suffix) => self.search(suffix),
+            Err(index) => index,
+        }
+    }
+
+    // the binary search over the slot array alone, to measure the hint array layout against
+    #[cfg(test)]
+    pub(crate) fn get_upper_bound_scalar(&self, key: &[u8]) -> usize {
+        match self.strip_prefix(key) {
+            Ok(suffix) => self
+                .data
+                .search_slots(suffix, false, |node| self.data.cmp_value_key(node, suffix)),
+            Err(index) => index,
+        }
+    }
+
+    fn search(&self, suffix: &[u8]) -> usize {
+        self.data
+            .search(suffix, false, |node| self.data.cmp_value_key(node, suffix))
+    }
+
+    // the part of `key` behind the prefix of the page. A key without the prefix sorts before or
+    // after every entry, the index it would go to is handed back instead
+    fn strip_prefix<'a>(&self, key: &'a [u8]) -> Result<&'a [u8], usize> {
+        let prefix = self.prefix();
+
+        match key.strip_prefix(prefix) {
+            Some(suffix) => Ok(suffix),
+            None if key < prefix => Err(0),
+            None => Err(self.size()),
+        }
     }
 
     // (more or less) shamelessly taken from https://users.rust-lang.org/t/how-to-find-common-prefix-of-two-byte-slices-effectively/25815/4
@@ -138,12 +430,12 @@ impl<T: Debug> SlottedLeaf<T> {
             .count()
     }
 
-    fn get_smallest_separator<'a>(
-        last_key: &'a str,
-        key: &'a str,
+    pub(crate) fn get_smallest_separator<'a>(
+        last_key: &'a [u8],
+        key: &'a [u8],
         current_best: u16,
-    ) -> Option<(&'a str, u16)> {
-        let sep_len = Self::common_prefix::<128>(last_key.as_bytes(), key.as_bytes()) + 1;
+    ) -> Option<(&'a [u8], u16)> {
+        let sep_len = Self::common_prefix::<128>(last_key, key) + 1;
 
         if sep_len >= current_best as usize {
             return None;
@@ -158,155 +450,376 @@ impl<T: Debug> SlottedLeaf<T> {
         return Some((separator, sep_len as u16));
     }
 
+    // picks the split point so both halves get about the same number of bytes, then looks at the
+    // split points up to `window` away from it for a shorter separator. `nodes` are the slots the
+    // leaf would have after the insert, with the new entry as the one starting at u16::MAX. Returns
+    // the index of the first entry that goes to the right leaf. The separator is only a suffix, like
+    // the keys it is taken from
     fn get_split<'a>(
-        &'a mut self,
-        overflow_node: &SlotNode,
-        new_slot: (&'a str, Node),
-    ) -> (usize, &'a str) {
-        let midpoint = (&self.header.node_count + 1) / 2;
+        &'a self,
+        nodes: &[SlotNode],
+        new_slot: (&'a [u8], &'a [u8]),
+        window: usize,
+    ) -> Result<(usize, Vec<u8>), BTreeError> {
+        let count = nodes.len();
+
+        let entry = |index: usize| self.data.get_overflow_value_area(&nodes[index], new_slot);
+        let suffix = |index: usize| self.data.read_key(entry(index).0);
+
+        // bytes[i] is what the first i entries take up
+        let mut bytes = vec![0];
+        for index in 0..count {
+            let (key, value) = entry(index);
+            bytes.push(bytes[index] + Self::entry_size(key, value));
+        }
 
-        let (nodes, _) = self.data.interpret(&self.header);
+        let total = bytes[count];
 
-        let split_range = &nodes[u16::max(midpoint - 1, 0) as usize
-            ..u16::min(midpoint + 2, self.header.node_count) as usize];
+        let mut midpoint = 1;
 
-        let mut key = self
-            .data
-            .get_overflow_heap_entry(&self.header, &split_range[0], new_slot)
-            .0;
+        while midpoint < count - 1 && bytes[midpoint] < total / 2 {
+            midpoint += 1;
+        }
+
+        // on large pages the split points right next to the midpoint always leave room for the
+        // fences. Further out, or on small pages where the fences take up a bigger share, one of
+        // the halves may end up too full. If none in the window fits, every split point is tried
+        let budget = Self::CAPACITY - MAX_FENCES_LEN;
 
-        let mut i = u16::max(midpoint - 1, 0) as usize;
         let mut split_index = 0;
         let mut separator_length = u16::MAX;
         let mut separator = None;
 
-        for node in &split_range[1..] {
-            let (next_key, _) = self
-                .data
-                .get_overflow_heap_entry(&self.header, node, new_slot);
+        for window in [window, count] {
+            for index in split_candidates(midpoint, window, 1, count - 1) {
+                if bytes[index] > budget || total - bytes[index] > budget {
+                    continue;
+                }
 
-            if let Some((sep, len)) = Self::get_smallest_separator(key, next_key, separator_length)
-            {
-                separator_length = len;
-                separator = Some(sep);
-                split_index = i + 1;
-            };
+                if let Some((sep, len)) = Self::get_smallest_separator(
+                    &suffix(index - 1),
+                    &suffix(index),
+                    separator_length,
+                ) {
+                    separator_length = len;
+                    separator = Some(sep.to_vec());
+                    split_index = index;
+                }
+            }
 
-            key = next_key;
-            i += 1;
+            if separator.is_some() {
+                break;
+            }
         }
 
-        // one last run for the  node if we excluded it before
-        if midpoint + 2 > self.header.node_count {
-            {
-                let (next_key, _) =
-                    self.data
-                        .get_overflow_heap_entry(&self.header, overflow_node, new_slot);
-
-                if let Some((sep, _)) =
-                    Self::get_smallest_separator(key, next_key, separator_length)
-                {
-                    separator = Some(sep);
-                    split_index = i + 1;
-                };
-            }
+        // only happens if two neighbouring keys are equal, which a sound page never has
+        let separator = separator.ok_or(BTreeError::NoSeparator)?;
+
+        Ok((split_index, separator))
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: Node) -> Result<InsertResultIntern, BTreeError> {
+        self.insert_with(key, &ptr_to_bytes(value), true, DEFAULT_SPLIT_WINDOW, &Heap)
+    }
+
+    pub fn insert_if_absent(
+        &mut self,
+        key: &[u8],
+        value: Node,
+    ) -> Result<InsertResultIntern, BTreeError> {
+        self.insert_with(
+            key,
+            &ptr_to_bytes(value),
+            false,
+            DEFAULT_SPLIT_WINDOW,
+            &Heap,
+        )
+    }
+
+    /// With `replace` set an existing value for `key` gets overwritten, otherwise it is left alone.
+    /// The value that was there before is handed back as a `V`. A full leaf is split at one of the
+    /// points up to `window` away from its middle, the right half goes into a page from `alloc`
+    pub fn insert_with<V: FromValue, A: PageAllocator>(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        replace: bool,
+        window: usize,
+        alloc: &A,
+    ) -> Result<InsertResultIntern<V>, BTreeError> {
+        if value.len() > Self::MAX_VALUE_LEN {
+            return Err(BTreeError::ValueTooLarge { len: value.len() });
         }
 
-        let Some(separator) = separator else {
-            panic!("Could not find a suitable separator");
+        let Ok(key) = self.strip_prefix(key) else {
+            // the key lies outside the fences, so it was routed to the wrong leaf
+            return Err(BTreeError::InvalidLayout);
         };
 
-        (split_index, separator)
-    }
+        let index = self.search(key);
+        let mut replaced = None;
 
-    pub fn insert(&mut self, key: &str, value: Node) -> InsertResultIntern {
-        let index = self.get_upper_bound(key);
+        if index < self.size() && self.is_suffix_at(index, key) {
+            let old_bytes = self.value_bytes_at(index);
+            let Some(old) = V::from_value(old_bytes) else {
+                return Err(BTreeError::ValueSize {
+                    len: old_bytes.len(),
+                });
+            };
 
-        let (nodes, _) = self.data.interpret(&self.header);
+            if !replace {
+                return Ok(InsertResultIntern::Present(old));
+            }
 
-        if index < self.header.node_count as usize
-            && self.data.get_heap_entry(&self.header, &nodes[index]).0 == key
-        {
-            return InsertResultIntern::Inserted;
+            if self.value_bytes_at(index).len() == value.len() {
+                self.data.overwrite_value_at(index, value);
+                self.mark_dirty(alloc);
+                return Ok(InsertResultIntern::Replaced(old));
+            }
+
+            replaced = Some(old);
         }
 
-        if self.can_fit(key) {
-            let node = self.data.add_heap_entry(&mut self.header, key, value);
+        // a replaced entry keeps its key and with it any overflow pages, a new one may need some.
+        // They go back to `alloc` if the insert fails after all
+        let stored_key = match replaced {
+            Some(_) => Cow::Owned(self.data.value_area_at(index).0.to_vec()),
+            None => Flex::<P>::store_key(self.prefix(), key, alloc)?,
+        };
+        let release = |err| {
+            if replaced.is_none() {
+                Flex::<P>::release_key(&stored_key, alloc);
+            }
+
+            err
+        };
+
+        let entry_bytes = Self::entry_size(&stored_key, value);
+
+        // a value of a different size needs a new heap entry, the old one only makes room for it
+        let freed = match replaced {
+            Some(_) => {
+                let node = self.data.interpret().0[index];
+                (node.end - node.start) as usize + SLOT_SIZE
+            }
+            None => 0,
+        };
+
+        if self.unused_bytes() + self.data.fragmented_bytes() + freed >= entry_bytes {
+            if replaced.is_some() {
+                self.data.remove_stack(index);
+            }
 
-            self.data.insert_stack(&mut self.header, index, node);
+            let fits = self.make_room(entry_bytes);
+            debug_assert!(fits);
 
-            return InsertResultIntern::Inserted;
+            let node = self.data.add_value_entry(&stored_key, value);
+
+            self.data.insert_stack(index, node);
+            self.mark_dirty(alloc);
+
+            return Ok(match replaced {
+                Some(old) => InsertResultIntern::Replaced(old),
+                None => InsertResultIntern::Inserted,
+            });
         }
 
-        let key_slice = if key.len() <= 4 { key } else { &key[0..4] };
+        // the split is worked out on a copy of the slots, so the page is left as it was if there
+        // is none
+        let mut nodes = self.data.interpret().0.to_vec();
 
-        let mut u32_bytes = [0; 4];
-        for (i, byte) in key_slice.bytes().enumerate() {
-            u32_bytes[i] = byte;
+        if replaced.is_some() {
+            nodes.remove(index);
         }
 
-        let end_node = self.data.insert_stack_overflow(
-            &self.header,
-            index,
-            SlotNode::new(u16::MAX, u16::MAX, u32::from_be_bytes(u32_bytes)),
-        );
-        let (index, separator) = self.get_split(&end_node, (key, value));
+        nodes.insert(index, SlotNode::new(u16::MAX, u16::MAX, first_bytes(key)));
 
-        // save a copy of separator, since it currently lives inside self, which will be replaced
-        let separator = separator.to_owned();
+        let (index, separator) = self
+            .get_split(&nodes, (&stored_key, value), window)
+            .map_err(release)?;
 
-        let (nodes, _) = self.data.interpret(&self.header);
+        // the separator is only a suffix, the prefix goes in front of it
+        let separator = [self.prefix(), &separator].concat();
 
         let (left_nodes, right_nodes) = nodes.split_at(index);
 
-        let left = Self::new_from_range(left_nodes, self, None, (key, value));
+        let lower = self.lower_fence();
+        let upper = self.upper_fence();
+
+        let mut left = Self::new_from_range(
+            left_nodes,
+            self,
+            (&stored_key, value),
+            lower,
+            Some(&separator),
+        );
+
+        let mut right =
+            Self::new_from_range(right_nodes, self, (&stored_key, value), &separator, upper);
+
+        left.set_prev_leaf(self.prev_leaf());
+        right.data.head_mut().pointer = self.data.head().pointer;
+        right.set_prev_leaf(NonNull::new(
+            alloc.reference(std::ptr::from_mut(self) as Node),
+        ));
+        let next = self.data.head().pointer;
 
-        let mut right = Self::new_from_range(right_nodes, self, Some(&end_node), (key, value));
+        left.check_fences();
+        right.check_fences();
 
-        right.header.pointer = self.header.pointer;
+        // the right leaf gets its page first, so the leaf is left as it was if there is none
+        let right_pointer = alloc.alloc_node(right).map_err(release)?;
 
-        // become the left leaf.
+        // become the left leaf. The entries of the old page have all moved, the overflow pages of
+        // their keys along with them.
         // this creates some extra work in the branch that points to this, but saves us having to
         // search the tree for the node that points to this and re-bend ITS pointer to the new
         // location.
         let _ = std::mem::replace(self, left);
 
-        let right_pointer = Box::into_raw(Box::new(right));
-        self.header.pointer = NonNull::new(right_pointer as Node);
+        self.data.head_mut().pointer = NonNull::new(right_pointer as Node);
+        self.mark_dirty(alloc);
+
+        Self::set_prev(next, right_pointer as Node, alloc);
+
+        Ok(InsertResultIntern::Split(
+            separator,
+            right_pointer as Node,
+            replaced,
+        ))
+    }
 
-        InsertResultIntern::Split(separator, right_pointer as Node)
+    /// The pointer stored for `key`. None if there is none, or if the entry holds a value of
+    /// another size
+    pub fn get(&self, key: &[u8]) -> Option<*mut T> {
+        self.get_value(key).and_then(FromValue::from_value)
     }
 
-    pub fn get(&self, key: &str) -> Option<*mut T> {
-        let index = self.get_upper_bound(key);
+    pub fn get_value(&self, key: &[u8]) -> Option<&[u8]> {
+        let key = self.strip_prefix(key).ok()?;
+        let index = self.search(key);
 
-        if index == self.size() {
+        if index == self.size() || !self.is_suffix_at(index, key) {
             return None;
         }
 
-        let node = self.data.interpret(&self.header).0[index];
-        let (entry_key, entry_value) = self.data.get_heap_entry(&self.header, &node);
+        Some(self.value_bytes_at(index))
+    }
+
+    /// Removes `key` and hands the overflow pages of the key, if any, back to `alloc`
+    pub fn remove<V: FromValue, A: PageAllocator>(&mut self, key: &[u8], alloc: &A) -> Option<V> {
+        let key = self.strip_prefix(key).ok()?;
+        let index = self.search(key);
 
-        if entry_key == key {
-            Some(entry_value as *mut T)
-        } else {
-            None
+        if index == self.size() || !self.is_suffix_at(index, key) {
+            return None;
         }
+
+        // a value that can't be read back as a `V` is left where it is
+        let value = V::from_value(self.value_bytes_at(index))?;
+        let removed = self.data.remove_stack(index);
+        self.data.release_value_key(&removed, alloc);
+        self.mark_dirty(alloc);
+
+        Some(value)
     }
 
-    pub fn print(&self) -> String {
+    pub fn can_merge(&self, right: &Self) -> bool {
+        // the merged leaf may share a shorter prefix, so the sizes of both pages don't just add up
+        let entries = self.stored_entries().chain(right.stored_entries());
+        Self::rebuilt_bytes(entries, self.lower_fence(), right.upper_fence()) <= Self::CAPACITY
+    }
+
+    /// Moves all entries of `right` into `self`, which also takes over its place in the leaf chain.
+    /// `right` has to be the next leaf and freeing it is up to the caller.
+    pub fn merge<A: PageAllocator>(&mut self, right: &Self, alloc: &A) {
+        let mut merged = Self::from_entries(
+            self.stored_entries().chain(right.stored_entries()),
+            self.lower_fence(),
+            right.upper_fence(),
+        );
+        merged.data.head_mut().pointer = right.data.head().pointer;
+        merged.set_prev_leaf(self.prev_leaf());
+
+        let _ = std::mem::replace(self, merged);
+
+        self.mark_dirty(alloc);
+        Self::set_prev(
+            self.data.head().pointer,
+            alloc.reference(std::ptr::from_mut(self) as Node),
+            alloc,
+        );
+        self.check_fences();
+    }
+
+    /// Spreads the entries of `self` and its right neighbour evenly over two new leaves. Nothing
+    /// is changed in place, since the parent may not have room for the new separator. None if the
+    /// new fences leave one of the halves without room for its entries.
+    pub fn redistribute(&self, right: &Self) -> Option<(Self, Self, Vec<u8>)> {
+        let entries: Vec<_> = self
+            .stored_entries()
+            .chain(right.stored_entries())
+            .collect();
+        let sizes: Vec<_> = entries
+            .iter()
+            .map(|entry| Self::moved_size(entry, 0))
+            .collect();
+        let half = sizes.iter().sum::<usize>() / 2;
+
+        let mut split_index = 1;
+        let mut left_bytes = sizes[0];
+
+        while split_index < entries.len() - 1 && left_bytes < half {
+            left_bytes += sizes[split_index];
+            split_index += 1;
+        }
+
+        let key = |index: usize| Flex::<P>::whole_key(entries[index].prefix, entries[index].key);
+        let (separator, _) =
+            Self::get_smallest_separator(&key(split_index - 1), &key(split_index), u16::MAX)
+                .map(|(separator, len)| (separator.to_owned(), len))
+                .expect("Leaf keys are not unique");
+        let separator = &separator[..];
+
+        let left_entries = || entries[..split_index].iter().copied();
+        let right_entries = || entries[split_index..].iter().copied();
+
+        let lower = self.lower_fence();
+        let upper = right.upper_fence();
+
+        if Self::rebuilt_bytes(left_entries(), lower, Some(separator)) > Self::CAPACITY
+            || Self::rebuilt_bytes(right_entries(), separator, upper) > Self::CAPACITY
+        {
+            return None;
+        }
+
+        let mut left = Self::from_entries(left_entries(), lower, Some(separator));
+        let mut right_leaf = Self::from_entries(right_entries(), separator, upper);
+
+        left.data.head_mut().pointer = self.data.head().pointer;
+        left.set_prev_leaf(self.prev_leaf());
+        right_leaf.data.head_mut().pointer = right.data.head().pointer;
+        right_leaf.set_prev_leaf(right.prev_leaf());
+
+        left.check_fences();
+        right_leaf.check_fences();
+
+        Some((left, right_leaf, separator.to_owned()))
+    }
+
+    /// The leaf as a graphviz node. `node` is what the other nodes refer to it by
+    pub fn print(&self, node: Node) -> String {
         let mut contents = String::new();
-        let self_ptr = std::ptr::from_ref(self) as usize;
+        let self_ptr = node as usize;
 
-        let (nodes, _) = self.data.interpret(&self.header);
-        for node in nodes {
-            let (key, ptr) = self.data.get_heap_entry(&self.header, node);
-            let value_ptr = ptr as usize;
+        for index in 0..self.size() {
+            // values are not necessarily pointers anymore, so the slot number names the port
+            let key = self.key_at(index);
 
             contents.push_str(&format!(
                 "<s{}> | {} | ",
-                value_ptr,
-                key[0..usize::min(key.len(), 10)]
+                index,
+                String::from_utf8_lossy(&key[0..usize::min(key.len(), 10)])
                     .replace("\n", "\\n")
                     .replace("\"", "\\\"")
             ));
@@ -314,9 +827,10 @@ impl<T: Debug> SlottedLeaf<T> {
 
         format!(
             "{}[label=\"{contents}<next>\"]\n{}:next -> {}\n",
-            std::ptr::from_ref(self) as usize,
             self_ptr,
-            self.header
+            self_ptr,
+            self.data
+                .head()
                 .pointer
                 .map(|p| p.as_ptr() as usize)
                 .or(Some(0))
@@ -325,28 +839,15 @@ impl<T: Debug> SlottedLeaf<T> {
     }
 }
 
-// we don't need this drop to free our contents, because they are pointers to data we don't own. so
-// whoever actually owns it will clean up
-
-// impl<T: Debug> Drop for SlottedLeaf<T> {
-//     fn drop(&mut self) {
-//         // we set the size to u16::MAX to indicate we don't want to drop children. So skip if we
-//         // see that special size
-//         if self.header.node_count != u16::MAX {
-//             let (nodes, _) = self.data.interpret(&self.header);
-//             for node in nodes {
-//                 let (_, ptr) = self.data.get_heap_entry(&self.header, node);
-//                 // re-box and drop
-//                 let _boxed = unsafe { Box::from_raw(ptr as *mut T) };
-//             }
-//         }
-//     }
-// }
-
 #[cfg(test)]
 mod leaf_tests {
 
-    use crate::{btree::InsertResultIntern, PTR_SIZE};
+    use crate::{
+        btree::{BTreeError, InsertResultIntern},
+        flex::{ptr_to_bytes, Flex, DEFAULT_SPLIT_WINDOW, MAX_INLINE_FENCE, VALUE_LEN_SIZE},
+        page_alloc::Heap,
+        PAGE_SIZE, PTR_SIZE,
+    };
 
     use super::SlottedLeaf;
 
@@ -355,11 +856,101 @@ mod leaf_tests {
         let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
 
         assert_eq!(
-            leaf.insert("hello", std::ptr::null_mut()),
+            leaf.insert(b"hello", std::ptr::null_mut()).unwrap(),
+            InsertResultIntern::Inserted
+        );
+
+        assert_ne!(leaf.get(b"hello"), None);
+    }
+
+    #[test]
+    fn replace() {
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
+
+        leaf.insert(b"hello", 11 as *mut ()).unwrap();
+
+        assert_eq!(
+            leaf.insert(b"hello", 12 as *mut ()).unwrap(),
+            InsertResultIntern::Replaced(11 as *mut ())
+        );
+
+        assert_eq!(
+            leaf.insert_if_absent(b"hello", 13 as *mut ()).unwrap(),
+            InsertResultIntern::Present(12 as *mut ())
+        );
+
+        assert_eq!(leaf.get(b"hello"), Some(12 as *mut ()));
+        assert_eq!(leaf.size(), 1);
+    }
+
+    #[test]
+    fn inline_values() {
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
+
+        assert_eq!(
+            leaf.insert_with::<Vec<u8>, _>(b"a", b"1234", true, DEFAULT_SPLIT_WINDOW, &Heap)
+                .unwrap(),
             InsertResultIntern::Inserted
         );
+        assert_eq!(
+            leaf.insert_with::<Vec<u8>, _>(b"b", b"", true, DEFAULT_SPLIT_WINDOW, &Heap)
+                .unwrap(),
+            InsertResultIntern::Inserted
+        );
+
+        // same size is overwritten in place, anything else gets a new heap entry
+        assert_eq!(
+            leaf.insert_with(b"a", b"5678", true, DEFAULT_SPLIT_WINDOW, &Heap)
+                .unwrap(),
+            InsertResultIntern::Replaced(b"1234".to_vec())
+        );
+        assert_eq!(
+            leaf.insert_with(b"a", b"longer value", true, DEFAULT_SPLIT_WINDOW, &Heap)
+                .unwrap(),
+            InsertResultIntern::Replaced(b"5678".to_vec())
+        );
+        assert_eq!(
+            leaf.insert_with(b"b", b"x", false, DEFAULT_SPLIT_WINDOW, &Heap)
+                .unwrap(),
+            InsertResultIntern::Present(vec![])
+        );
+
+        assert_eq!(leaf.get_value(b"a"), Some(&b"longer value"[..]));
+        assert_eq!(leaf.get_value(b"b"), Some(&b""[..]));
+        assert_eq!(leaf.get_value(b"c"), None);
 
-        assert_ne!(leaf.get("hello"), None);
+        assert_eq!(
+            leaf.remove::<Vec<u8>, _>(b"a", &Heap),
+            Some(b"longer value".to_vec())
+        );
+        assert_eq!(leaf.size(), 1);
+    }
+
+    #[test]
+    fn overflow_keys() {
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
+        let long = vec![7; 64 * 1024];
+        let longer = [&long[..], b"!"].concat();
+
+        leaf.insert(&long, 11 as *mut ()).unwrap();
+        leaf.insert(&longer, 12 as *mut ()).unwrap();
+        leaf.insert(b"", 13 as *mut ()).unwrap();
+
+        // only stubs are kept in the page
+        assert!(
+            leaf.payload_bytes()
+                < 2 * (Flex::<PAGE_SIZE>::MAX_INLINE_KEY + 1 + VALUE_LEN_SIZE + PTR_SIZE) + 16
+        );
+
+        assert_eq!(leaf.key_at(0), &b""[..]);
+        assert_eq!(leaf.key_at(1), &long[..]);
+        assert_eq!(leaf.key_at(2), &longer[..]);
+        assert_eq!(leaf.get(&longer), Some(12 as *mut ()));
+        assert_eq!(leaf.get(&long[..1000]), None);
+
+        assert_eq!(leaf.remove(&long, &Heap), Some(11 as *mut ()));
+        assert_eq!(leaf.get(&long), None);
+        assert_eq!(leaf.get(&longer), Some(12 as *mut ()));
     }
 
     #[test]
@@ -372,24 +963,26 @@ mod leaf_tests {
             for el in stream {
                 let str = format!("{:08}", el);
 
-                if !leaf.can_fit(&str) {
+                if !leaf.can_fit(str.as_bytes(), &ptr_to_bytes(std::ptr::null_mut())) {
                     overflow_key = str;
                     break;
                 }
 
-                leaf.insert(&str, std::ptr::null_mut());
+                leaf.insert(str.as_bytes(), std::ptr::null_mut()).unwrap();
             }
             // leaf is now before splitting
             let page_size = leaf.size();
             let page_bytes = leaf.payload_bytes();
 
-            let res = leaf.insert(&overflow_key, std::ptr::null_mut());
+            let res = leaf
+                .insert(overflow_key.as_bytes(), std::ptr::null_mut())
+                .unwrap();
             let (_, left_tree) = match res {
                 InsertResultIntern::Inserted => panic!("Leaf did not split"),
-                InsertResultIntern::Replaced(_) => {
+                InsertResultIntern::Replaced(_) | InsertResultIntern::Present(_) => {
                     panic!("Either you got insanely lucky, or you just replaced some random value");
                 }
-                InsertResultIntern::Split(a, b) => (a, b),
+                InsertResultIntern::Split(a, b, _) => (a, b),
             };
 
             let left = unsafe { &mut *(left_tree as *mut SlottedLeaf<()>) };
@@ -397,11 +990,70 @@ mod leaf_tests {
             assert_eq!(left.size() + leaf.size(), page_size + 1);
             assert_eq!(
                 left.payload_bytes() + leaf.payload_bytes(),
-                page_bytes + overflow_key.len() + PTR_SIZE
+                page_bytes + overflow_key.len() + VALUE_LEN_SIZE + PTR_SIZE
             );
         }
     }
 
+    #[test]
+    fn compaction() {
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
+        let mut count = 0;
+
+        while leaf.can_fit(
+            format!("{count:08}").as_bytes(),
+            &ptr_to_bytes(std::ptr::null_mut()),
+        ) {
+            leaf.insert(format!("{count:08}").as_bytes(), count as *mut ())
+                .unwrap();
+            count += 1;
+        }
+
+        for i in (0..count).step_by(2) {
+            leaf.remove::<*mut (), _>(format!("{i:08}").as_bytes(), &Heap);
+        }
+
+        // the holes are taken back instead of splitting the leaf
+        let payload_bytes = leaf.payload_bytes();
+
+        for i in (0..count).step_by(2) {
+            assert_eq!(
+                leaf.insert(format!("{i:08}").as_bytes(), i as *mut ())
+                    .unwrap(),
+                InsertResultIntern::Inserted
+            );
+        }
+
+        assert!(leaf.payload_bytes() <= payload_bytes);
+
+        for i in 0..count {
+            assert_eq!(leaf.get(format!("{i:08}").as_bytes()), Some(i as *mut ()));
+        }
+
+        // values that change size leave holes behind as well
+        for i in 0..count {
+            let value = if i % 2 == 0 {
+                &b"12345678"[..]
+            } else {
+                b"1234"
+            };
+
+            assert_eq!(
+                leaf.insert_with::<Vec<u8>, _>(
+                    format!("{i:08}").as_bytes(),
+                    value,
+                    true,
+                    DEFAULT_SPLIT_WINDOW,
+                    &Heap,
+                )
+                .unwrap(),
+                InsertResultIntern::Replaced(ptr_to_bytes(i as *mut ()).to_vec())
+            );
+        }
+
+        assert_eq!(leaf.size(), count);
+    }
+
     #[test]
     fn sort_order() {
         for _ in 0..100 {
@@ -411,11 +1063,11 @@ mod leaf_tests {
             for el in stream {
                 let str = format!("{:08}", el);
 
-                if !leaf.can_fit(&str) {
+                if !leaf.can_fit(str.as_bytes(), &ptr_to_bytes(std::ptr::null_mut())) {
                     break;
                 }
 
-                leaf.insert(&str, std::ptr::null_mut());
+                leaf.insert(str.as_bytes(), std::ptr::null_mut()).unwrap();
             }
             // leaf is now before splitting
             let mut prev_key = leaf.key_at(0);
@@ -426,4 +1078,199 @@ mod leaf_tests {
             }
         }
     }
+
+    #[test]
+    fn search() {
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
+
+        // keys that tie on their first four bytes, including the zero padding of short keys
+        let mut keys: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0],
+            vec![0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0],
+            b"ab".to_vec(),
+            b"ab\0".to_vec(),
+            b"ab\0\0x".to_vec(),
+            b"abcd".to_vec(),
+            b"abcde".to_vec(),
+            b"abcdf".to_vec(),
+            vec![0xFF; 4],
+            vec![0xFF; 5],
+        ];
+
+        for key in keys.iter().rev() {
+            leaf.insert(key, std::ptr::null_mut()).unwrap();
+        }
+
+        keys.extend([
+            b"a".to_vec(),
+            b"abc".to_vec(),
+            b"abcdd".to_vec(),
+            vec![0xFF; 6],
+        ]);
+
+        for key in &keys {
+            let expected = (0..leaf.size())
+                .position(|i| *leaf.key_at(i) >= **key)
+                .unwrap_or(leaf.size());
+
+            assert_eq!(leaf.get_upper_bound(key), expected, "{key:?}");
+        }
+    }
+
+    #[test]
+    fn prefix_compression() {
+        let mut plain: SlottedLeaf<()> = SlottedLeaf::new();
+        let mut fenced: SlottedLeaf<()> =
+            SlottedLeaf::with_fences(b"users/0042/", Some(b"users/0043"));
+
+        assert_eq!(fenced.prefix(), b"users/004");
+
+        for i in 0..50 {
+            let key = format!("users/0042/{i:04}");
+            plain.insert(key.as_bytes(), i as *mut ()).unwrap();
+            fenced.insert(key.as_bytes(), i as *mut ()).unwrap();
+        }
+
+        // only the part behind the prefix is stored
+        assert_eq!(
+            plain.payload_bytes() - fenced.payload_bytes(),
+            50 * b"users/004".len()
+        );
+
+        assert_eq!(fenced.key_at(7), &b"users/0042/0007"[..]);
+        assert!(fenced.is_key_at(7, b"users/0042/0007"));
+        assert_eq!(fenced.get(b"users/0042/0007"), Some(7 as *mut ()));
+        assert_eq!(fenced.get(b"users/0041/0007"), None);
+        assert_eq!(fenced.get_upper_bound(b"users/0041"), 0);
+        assert_eq!(fenced.get_upper_bound(b"users/0050"), 50);
+
+        assert_eq!(
+            fenced.insert(b"users/0050", std::ptr::null_mut()),
+            Err(BTreeError::InvalidLayout)
+        );
+
+        // the halves of a split get the separator as a fence and share at least as much as before
+        let mut i = 50;
+
+        let right = loop {
+            let key = format!("users/0042/{i:04}");
+
+            if let InsertResultIntern::Split(_, right, _) =
+                fenced.insert(key.as_bytes(), i as *mut ()).unwrap()
+            {
+                break unsafe { Box::from_raw(right as *mut SlottedLeaf<()>) };
+            }
+
+            i += 1;
+        };
+
+        assert_eq!(fenced.lower_fence(), b"users/0042/");
+        assert_eq!(Some(right.lower_fence()), fenced.upper_fence());
+        assert_eq!(right.upper_fence(), Some(&b"users/0043"[..]));
+        assert!(fenced.prefix().starts_with(b"users/0042/"));
+        assert_eq!(right.prefix(), b"users/004");
+
+        for i in 0..=i {
+            let key = format!("users/0042/{i:04}");
+            let leaf = if fenced.get(key.as_bytes()).is_some() {
+                &fenced
+            } else {
+                &right
+            };
+
+            assert_eq!(leaf.get(key.as_bytes()), Some(i as *mut ()));
+        }
+    }
+
+    #[test]
+    fn long_fences() {
+        let lower = [b'a'; 100];
+        let mut upper = lower.to_vec();
+        upper[50] = b'b';
+
+        // both get cut short, the upper one to the next key after its first bytes
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::with_fences(&lower, Some(&upper));
+        let mut short_upper = lower[..MAX_INLINE_FENCE].to_vec();
+        short_upper[MAX_INLINE_FENCE - 1] += 1;
+
+        assert_eq!(leaf.lower_fence(), &lower[..MAX_INLINE_FENCE]);
+        assert_eq!(leaf.upper_fence(), Some(&short_upper[..]));
+        assert_eq!(leaf.prefix(), &lower[..MAX_INLINE_FENCE - 1]);
+
+        let key = [&lower[..], b"!"].concat();
+        leaf.insert(&key, std::ptr::dangling_mut()).unwrap();
+        assert_eq!(leaf.get(&key), Some(std::ptr::dangling_mut()));
+        leaf.check_fences();
+
+        // no key of at most MAX_INLINE_FENCE bytes is larger than this one, so there is no bound
+        let leaf: SlottedLeaf<()> = SlottedLeaf::with_fences(b"", Some(&[0xFF; 40]));
+        assert_eq!(leaf.upper_fence(), None);
+    }
+
+    #[cfg(feature = "simd-hints")]
+    #[test]
+    fn hint_array() {
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
+
+        let check = |leaf: &SlottedLeaf<()>| {
+            let (nodes, _) = leaf.data.interpret();
+            let hints: Vec<_> = nodes.iter().map(|node| node.first_bytes).collect();
+
+            assert_eq!(leaf.data.hints(), hints);
+        };
+
+        for i in 0..100u32 {
+            leaf.insert(&(i * 37 % 100).to_be_bytes(), std::ptr::null_mut())
+                .unwrap();
+            check(&leaf);
+        }
+
+        for i in (0..100u32).step_by(3) {
+            leaf.remove::<*mut (), _>(&i.to_be_bytes(), &Heap);
+            check(&leaf);
+        }
+
+        // fill up until the leaf splits, which shifts the hints of the overflowing slot array
+        let mut i = 1000u32;
+
+        loop {
+            let res = leaf.insert(&i.to_be_bytes(), std::ptr::null_mut()).unwrap();
+            check(&leaf);
+
+            if let InsertResultIntern::Split(_, right, _) = res {
+                let right = unsafe { Box::from_raw(right as *mut SlottedLeaf<()>) };
+                check(&right);
+                break;
+            }
+
+            i += 7;
+        }
+    }
+
+    #[test]
+    fn remove() {
+        let mut leaf: SlottedLeaf<()> = SlottedLeaf::new();
+
+        for i in 0..100 {
+            leaf.insert(format!("{i:04}").as_bytes(), i as *mut ())
+                .unwrap();
+        }
+
+        for i in (0..100).step_by(2) {
+            assert_eq!(
+                leaf.remove(format!("{i:04}").as_bytes(), &Heap),
+                Some(i as *mut ())
+            );
+        }
+
+        assert_eq!(leaf.remove::<*mut (), _>(b"0000", &Heap), None);
+        assert_eq!(leaf.size(), 50);
+
+        for i in 0..100 {
+            let expected = (i % 2 == 1).then_some(i as *mut ());
+            assert_eq!(leaf.get(format!("{i:04}").as_bytes()), expected);
+        }
+    }
 }